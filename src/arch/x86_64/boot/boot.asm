@@ -1,4 +1,6 @@
 global start
+global stack_bottom
+global stack_top
 
 section .multiboot_header
 header_start:
@@ -20,17 +22,29 @@ bits 32
 start:
     ;stack pointer points to stack_top (stack grows from higher memory addresses to lower)
     mov esp, stack_top
-    mov edi, ebx       ; Move Multiboot info pointer to edi
 
     ;check if the kernel is started by multiboot compliant header
     call check_multiboot
 
+    ;paint the stack, the deepest usage is found later by looking for the first overwritten word.
+    ;it is done after the multiboot check, which needs the magic in eax, and nothing is left
+    ;on the stack once the check returned
+    mov edi, stack_bottom
+    mov ecx, (stack_top - stack_bottom) / 4
+    mov eax, 0x57ac57ac
+    cld
+    rep stosd
+
+    mov edi, ebx       ; Move Multiboot info pointer to edi
+
     ;check if hardware is new enough to run our kernel
     call check_cpuid
     call check_long_mode
 
     ;identity map first 1GB (512 X 2MB) of memory to our kernel
     call set_up_page_tables
+    ;put a P5 table on top if the CPU supports five-level paging
+    call set_up_p5_table
     call enable_paging
 
     ; load the 64-bit Global Descriptor Table
@@ -141,14 +155,53 @@ set_up_page_tables:
 
     ret
 
+set_up_p5_table:
+    ; test if structured extended feature flags are available
+    mov eax, 0             ; implicit argument for cpuid
+    cpuid                  ; get highest supported basic argument
+    cmp eax, 7             ; it needs to be at least 7
+    jb .no_la57
+
+    ; test the LA57 bit of the structured extended feature flags
+    mov eax, 7
+    mov ecx, 0
+    cpuid
+    test ecx, 1 << 16
+    jz .no_la57
+
+    ; map first P5 entry to P4 table
+    mov eax, p4_table
+    or eax, 0b11 ; present + writable
+    mov [p5_table], eax
+
+    ; map last P5 entry to P5 table, the recursive entry moves from P4 to P5
+    mov eax, p5_table
+    or eax, 0b11 ; present + writable
+    mov [p5_table + 511 * 8], eax
+    mov dword [p4_table + 511 * 8], 0
+
+    mov byte [la57_enabled], 1
+.no_la57:
+    ret
+
 enable_paging:
-    ; load P4 to cr3 register (cpu uses this to access the P4 table)
+    ; load P4 (or P5 with five-level paging) to cr3 register
+    ; (cpu uses this to access the top level table)
     mov eax, p4_table
+    cmp byte [la57_enabled], 0
+    je .load_cr3
+    mov eax, p5_table
+.load_cr3:
     mov cr3, eax
 
     ; enable PAE-flag in cr4 (Physical Address Extension)
+    ; and LA57-flag if the P5 table is used
     mov eax, cr4
     or eax, 1 << 5
+    cmp byte [la57_enabled], 0
+    je .set_cr4
+    or eax, 1 << 12
+.set_cr4:
     mov cr4, eax
 
     ; set the long mode bit in the EFER MSR (model specific register)
@@ -176,7 +229,10 @@ long_mode_start:
     mov gs, ax
 
     call clear_screen
-    
+
+    ; terminate the frame pointer chain for stack walks
+    xor rbp, rbp
+
     ; call the rust main
     extern _start
     call _start
@@ -197,6 +253,8 @@ clear_screen:
 
 section .bss
 align 4096
+p5_table:
+    resb 4096
 p4_table:
     resb 4096
 p3_table:
@@ -206,6 +264,8 @@ p2_table:
 stack_bottom:
     resb 4096 * 4
 stack_top:
+la57_enabled:
+    resb 1
 
 section .rodata
 gdt64:
@@ -0,0 +1,151 @@
+use core::arch::x86_64::{__cpuid_count, CpuidResult};
+
+/// Interrupt enable flag in RFLAGS
+const INTERRUPT_FLAG: u64 = 1 << 9;
+
+bitflags! {
+    pub struct Cr4Flags: u64 {
+        const PHYSICAL_ADDRESS_EXTENSION = 1 << 5;
+        const MACHINE_CHECK =              1 << 6;
+        const PAGE_GLOBAL =                1 << 7;
+        const OSFXSR =                     1 << 9;
+        const LA57 =                       1 << 12;
+        const PROTECTION_KEY =             1 << 22;
+    }
+}
+
+pub fn read_cr0() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov %cr0, $0" : "=r"(value) ::: "volatile"); }
+    value
+}
+
+/// Address that caused the last page fault
+pub fn read_cr2() -> usize {
+    let value: usize;
+    unsafe { asm!("mov %cr2, $0" : "=r"(value) ::: "volatile"); }
+    value
+}
+
+pub fn read_cr3() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov %cr3, $0" : "=r"(value) ::: "volatile"); }
+    value
+}
+
+pub fn read_cr4() -> Cr4Flags {
+    Cr4Flags::from_bits_truncate(read_cr4_raw())
+}
+
+pub fn read_cr4_raw() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov %cr4, $0" : "=r"(value) ::: "volatile"); }
+    value
+}
+
+/// Write the CR4 register, preserving bits that have no flag
+pub unsafe fn write_cr4(flags: Cr4Flags) {
+    let value = (read_cr4_raw() & !Cr4Flags::all().bits()) | flags.bits();
+    asm!("mov $0, %cr4" :: "r"(value) : "memory" : "volatile");
+}
+
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    unsafe { __cpuid_count(leaf, subleaf) }
+}
+
+/// Highest supported basic CPUID leaf
+pub fn max_cpuid_leaf() -> u32 {
+    cpuid(0, 0).eax
+}
+
+/// Whether the CPU identifies itself as "GenuineIntel"
+pub fn is_intel() -> bool {
+    let vendor = cpuid(0, 0);
+    vendor.ebx == 0x756e_6547 && vendor.edx == 0x4965_6e69 && vendor.ecx == 0x6c65_746e
+}
+
+/// Family and model of the CPU, including the extended family and model
+pub fn family_model() -> (u32, u32) {
+    let signature = cpuid(1, 0).eax;
+    let mut family = (signature >> 8) & 0xf;
+    let mut model = (signature >> 4) & 0xf;
+    if family == 0x6 || family == 0xf {
+        model += ((signature >> 16) & 0xf) << 4;
+    }
+    if family == 0xf {
+        family += (signature >> 20) & 0xff;
+    }
+    (family, model)
+}
+
+/// CPUID.(EAX=1):EDX, basic feature flags
+fn features_edx() -> u32 {
+    cpuid(1, 0).edx
+}
+
+/// CPUID.(EAX=7,ECX=0):ECX, structured extended feature flags
+fn extended_features_ecx() -> u32 {
+    if max_cpuid_leaf() >= 7 {
+        cpuid(7, 0).ecx
+    } else {
+        0
+    }
+}
+
+/// Protection keys for user-mode pages
+pub fn has_pku() -> bool {
+    extended_features_ecx() & (1 << 3) != 0
+}
+
+/// Machine check exception
+pub fn has_mce() -> bool {
+    features_edx() & (1 << 7) != 0
+}
+
+/// Machine check architecture, the error reporting banks
+pub fn has_mca() -> bool {
+    features_edx() & (1 << 14) != 0
+}
+
+/// On-chip local APIC
+pub fn has_apic() -> bool {
+    features_edx() & (1 << 9) != 0
+}
+
+/// Local APIC can be run in x2APIC mode
+pub fn has_x2apic() -> bool {
+    cpuid(1, 0).ecx & (1 << 21) != 0
+}
+
+pub fn read_msr(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    unsafe { asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) :: "volatile"); }
+    (high as u64) << 32 | low as u64
+}
+
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    let low = value as u32;
+    let high = (value >> 32) as u32;
+    asm!("wrmsr" :: "{ecx}"(msr), "{eax}"(low), "{edx}"(high) : "memory" : "volatile");
+}
+
+/// Time stamp counter, counts cycles at a constant rate on recent CPUs
+pub fn rdtsc() -> u64 {
+    let (high, low): (u32, u32);
+    unsafe { asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile"); }
+    (high as u64) << 32 | low as u64
+}
+
+/// Disable interrupts, returns whether they were enabled before
+pub fn disable_interrupts() -> bool {
+    let flags: u64;
+    unsafe { asm!("pushfq; popq $0; cli" : "=r"(flags) ::: "memory" : "volatile"); }
+    flags & INTERRUPT_FLAG != 0
+}
+
+/// Enable interrupts again if they were enabled before `disable_interrupts`
+pub fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe { asm!("sti" :::: "memory" : "volatile"); }
+    }
+}
@@ -0,0 +1,234 @@
+//! Discovery of the ACPI tables. Only the MADT, which describes the
+//! interrupt controllers, is parsed so far.
+
+use core::mem;
+use core::ptr;
+use core::slice;
+
+use alloc::vec::Vec;
+
+use memory::physical_map::map_table;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+/// Real mode segment of the extended BIOS data area is stored here
+const EBDA_SEGMENT_POINTER: usize = 0x40e;
+const EBDA_SEARCH_SIZE: usize = 1024;
+const BIOS_AREA_START: usize = 0xe0000;
+const BIOS_AREA_END: usize = 0x100000;
+/// The RSDP is aligned to 16 bytes
+const RSDP_ALIGN: usize = 16;
+/// Bytes covered by the checksum of an ACPI 1.0 RSDP
+const RSDP_V1_SIZE: usize = 20;
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // since ACPI 2.0
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header common to all system description tables
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// A processor with its local APIC
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub processor_id: u32,
+    /// x2APIC IDs don't fit into the 8 bits of the xAPIC entries
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: usize,
+    /// First global system interrupt handled by the I/O APIC
+    pub gsi_base: u32,
+}
+
+/// ISA IRQ that is not connected to the I/O APIC input of the same number
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// Multiple APIC description table
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: usize,
+    /// The system also has the two 8259 PICs
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+fn checksum_valid(address: usize, length: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(address as *const u8, length) };
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+unsafe fn read<T: Copy>(address: usize) -> T {
+    ptr::read_unaligned(address as *const T)
+}
+
+/// Search `size` bytes of physical memory at `start` for the RSDP
+fn search_rsdp(start: usize, size: usize) -> Option<usize> {
+    let virtual_start = map_table(start, size);
+    (0..size / RSDP_ALIGN).map(|i| virtual_start + i * RSDP_ALIGN).find(|&address| {
+        let signature = unsafe { slice::from_raw_parts(address as *const u8, RSDP_SIGNATURE.len()) };
+        signature == RSDP_SIGNATURE && checksum_valid(address, RSDP_V1_SIZE)
+    })
+}
+
+fn find_rsdp() -> Option<usize> {
+    let ebda_segment: u16 = unsafe { read(map_table(EBDA_SEGMENT_POINTER, 2)) };
+    let ebda = (ebda_segment as usize) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = search_rsdp(ebda, EBDA_SEARCH_SIZE) {
+            return Some(rsdp);
+        }
+    }
+    search_rsdp(BIOS_AREA_START, BIOS_AREA_END - BIOS_AREA_START)
+}
+
+/// Map a whole table and return its virtual address, if its checksum is valid
+fn map_sdt(address: usize) -> Option<usize> {
+    let header = map_table(address, mem::size_of::<SdtHeader>());
+    let length = unsafe { (*(header as *const SdtHeader)).length } as usize;
+    let table = map_table(address, length);
+    if checksum_valid(table, length) {
+        Some(table)
+    } else {
+        None
+    }
+}
+
+/// Find the table with `signature` through the XSDT, or the RSDT before ACPI 2.0
+fn find_table(signature: &[u8; 4]) -> Option<usize> {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => return None,
+    };
+
+    let (revision, rsdt_address, xsdt_address) = unsafe {
+        let rsdp = &*(rsdp as *const Rsdp);
+        (rsdp.revision, rsdp.rsdt_address, rsdp.xsdt_address)
+    };
+    let (root, entry_size) = if revision >= 2 && xsdt_address != 0 {
+        (xsdt_address as usize, 8)
+    } else {
+        (rsdt_address as usize, 4)
+    };
+
+    let root = match map_sdt(root) {
+        Some(root) => root,
+        None => return None,
+    };
+    let length = unsafe { (*(root as *const SdtHeader)).length } as usize;
+    let entries = (length - mem::size_of::<SdtHeader>()) / entry_size;
+
+    for i in 0..entries {
+        let entry = root + mem::size_of::<SdtHeader>() + i * entry_size;
+        let address = unsafe {
+            if entry_size == 8 { read::<u64>(entry) as usize } else { read::<u32>(entry) as usize }
+        };
+        if let Some(table) = map_sdt(address) {
+            let table_signature = unsafe { (*(table as *const SdtHeader)).signature };
+            if &table_signature == signature {
+                return Some(table);
+            }
+        }
+    }
+    None
+}
+
+/// Find and parse the MADT
+pub fn madt() -> Option<Madt> {
+    let table = match find_table(MADT_SIGNATURE) {
+        Some(table) => table,
+        None => return None,
+    };
+
+    let length = unsafe { (*(table as *const SdtHeader)).length } as usize;
+    let fields = table + mem::size_of::<SdtHeader>();
+    let mut madt = unsafe {
+        Madt {
+            local_apic_address: read::<u32>(fields) as usize,
+            has_legacy_pics: read::<u32>(fields + 4) & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        }
+    };
+
+    // variable length entries starting with type and length
+    let mut entry = fields + 8;
+    while entry + 2 <= table + length {
+        let (entry_type, entry_length) = unsafe { (read::<u8>(entry), read::<u8>(entry + 1) as usize) };
+        if entry_length < 2 {
+            break;
+        }
+
+        unsafe {
+            match entry_type {
+                0 => madt.processors.push(Processor {
+                    processor_id: read::<u8>(entry + 2) as u32,
+                    apic_id: read::<u8>(entry + 3) as u32,
+                    enabled: read::<u32>(entry + 4) & 1 != 0,
+                }),
+                1 => madt.io_apics.push(IoApicEntry {
+                    id: read(entry + 2),
+                    address: read::<u32>(entry + 4) as usize,
+                    gsi_base: read(entry + 8),
+                }),
+                // only overrides of ISA IRQs (bus 0) exist
+                2 => {
+                    let flags: u16 = read(entry + 8);
+                    madt.overrides.push(InterruptOverride {
+                        irq: read(entry + 3),
+                        gsi: read(entry + 4),
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: (flags >> 2) & 0b11 == 0b11,
+                    });
+                }
+                // 64-bit local APIC address
+                5 => madt.local_apic_address = read::<u64>(entry + 4) as usize,
+                // processor with an x2APIC ID
+                9 => madt.processors.push(Processor {
+                    processor_id: read(entry + 12),
+                    apic_id: read(entry + 4),
+                    enabled: read::<u32>(entry + 8) & 1 != 0,
+                }),
+                _ => {}
+            }
+        }
+        entry += entry_length;
+    }
+
+    Some(madt)
+}
@@ -0,0 +1,115 @@
+use core::ptr;
+
+use memory::physical_map::map_mmio;
+
+const REGISTER_SELECT: usize = 0x00;
+const REGISTER_WINDOW: usize = 0x10;
+
+const VERSION_REGISTER: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10;
+
+const REGISTERS_SIZE: usize = 0x20;
+
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+
+/// How an input of an I/O APIC is delivered
+#[derive(Debug, Clone, Copy)]
+pub struct Redirection {
+    pub vector: u8,
+    pub destination: u8,
+    pub active_low: bool,
+    pub level_triggered: bool,
+    pub masked: bool,
+}
+
+impl Redirection {
+    fn bits(&self) -> u64 {
+        let mut bits = self.vector as u64 | (self.destination as u64) << 56;
+        if self.active_low {
+            bits |= ACTIVE_LOW;
+        }
+        if self.level_triggered {
+            bits |= LEVEL_TRIGGERED;
+        }
+        if self.masked {
+            bits |= MASKED;
+        }
+        bits
+    }
+}
+
+/// An I/O APIC handling the global system interrupts from `gsi_base` on.
+/// The registers are accessed through a select and a window register, so
+/// it has to be protected by a lock.
+pub struct IoApic {
+    id: u8,
+    base: usize,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    /// Map the registers at physical address `address`
+    pub fn new(id: u8, address: usize, gsi_base: u32) -> IoApic {
+        let mut io_apic = IoApic {
+            id: id,
+            base: map_mmio(address, REGISTERS_SIZE),
+            gsi_base: gsi_base,
+            inputs: 0,
+        };
+        io_apic.inputs = ((io_apic.read(VERSION_REGISTER) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + REGISTER_SELECT) as *mut u32, register);
+            ptr::read_volatile((self.base + REGISTER_WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + REGISTER_SELECT) as *mut u32, register);
+            ptr::write_volatile((self.base + REGISTER_WINDOW) as *mut u32, value);
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.inputs
+    }
+
+    fn entry_register(&self, gsi: u32) -> u32 {
+        debug_assert!(self.handles(gsi));
+        REDIRECTION_TABLE + (gsi - self.gsi_base) * 2
+    }
+
+    pub fn set_redirection(&self, gsi: u32, redirection: Redirection) {
+        let register = self.entry_register(gsi);
+        let bits = redirection.bits();
+        // mask the entry while it is changed
+        self.write(register, MASKED as u32);
+        self.write(register + 1, (bits >> 32) as u32);
+        self.write(register, bits as u32);
+    }
+
+    pub fn set_masked(&self, gsi: u32, masked: bool) {
+        let register = self.entry_register(gsi);
+        let low = self.read(register) as u64;
+        let low = if masked { low | MASKED } else { low & !MASKED };
+        self.write(register, low as u32);
+    }
+
+    /// Mask all inputs
+    pub fn mask_all(&self) {
+        for i in 0..self.inputs {
+            self.set_masked(self.gsi_base + i, true);
+        }
+    }
+}
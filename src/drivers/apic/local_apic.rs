@@ -0,0 +1,149 @@
+use core::ptr;
+
+use cpu;
+use memory::physical_map::map_mmio;
+
+const APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+
+/// In x2APIC mode the register at MMIO offset `n` is MSR `X2APIC_MSR_BASE + n / 16`
+const X2APIC_MSR_BASE: u32 = 0x800;
+/// x2APIC only, sends an interrupt to the current CPU
+const X2APIC_SELF_IPI_MSR: u32 = 0x83f;
+
+const ID_REGISTER: usize = 0x20;
+const TASK_PRIORITY_REGISTER: usize = 0x80;
+const EOI_REGISTER: usize = 0xb0;
+const SPURIOUS_VECTOR_REGISTER: usize = 0xf0;
+const ICR_LOW_REGISTER: usize = 0x300;
+const ICR_HIGH_REGISTER: usize = 0x310;
+const LVT_TIMER_REGISTER: usize = 0x320;
+const LVT_PERFORMANCE_COUNTER_REGISTER: usize = 0x340;
+const LVT_ERROR_REGISTER: usize = 0x370;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DESTINATION_SELF: u32 = 0b01 << 18;
+
+const REGISTERS_SIZE: usize = 0x400;
+
+enum Mode {
+    /// Registers mapped at `base`
+    XApic { base: usize },
+    /// Registers accessed through MSRs
+    X2Apic,
+}
+
+/// The local APIC of the current CPU. It is run in x2APIC mode if the CPU
+/// supports it, with the memory mapped xAPIC registers as fallback.
+pub struct LocalApic {
+    mode: Mode,
+}
+
+impl LocalApic {
+    /// `address` is the physical address of the xAPIC registers
+    pub fn new(address: usize) -> LocalApic {
+        let mode = if cpu::has_x2apic() {
+            Mode::X2Apic
+        } else {
+            Mode::XApic { base: map_mmio(address, REGISTERS_SIZE) }
+        };
+        LocalApic { mode: mode }
+    }
+
+    fn read(&self, register: usize) -> u32 {
+        match self.mode {
+            Mode::XApic { base } => unsafe { ptr::read_volatile((base + register) as *const u32) },
+            Mode::X2Apic => cpu::read_msr(X2APIC_MSR_BASE + (register >> 4) as u32) as u32,
+        }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        match self.mode {
+            Mode::XApic { base } => unsafe { ptr::write_volatile((base + register) as *mut u32, value) },
+            Mode::X2Apic => unsafe { cpu::write_msr(X2APIC_MSR_BASE + (register >> 4) as u32, value as u64) },
+        }
+    }
+
+    pub fn is_x2apic(&self) -> bool {
+        match self.mode {
+            Mode::X2Apic => true,
+            Mode::XApic { .. } => false,
+        }
+    }
+
+    /// Enable the APIC, deliver spurious interrupts on `spurious_vector`
+    /// and accept interrupts of all priorities
+    pub fn init(&self, spurious_vector: u8) {
+        unsafe {
+            let mut base = cpu::read_msr(APIC_BASE_MSR) | APIC_BASE_ENABLE;
+            cpu::write_msr(APIC_BASE_MSR, base);
+            // x2APIC mode can only be entered from the enabled xAPIC mode
+            if self.is_x2apic() {
+                base |= APIC_BASE_X2APIC_ENABLE;
+                cpu::write_msr(APIC_BASE_MSR, base);
+            }
+        }
+        self.write(SPURIOUS_VECTOR_REGISTER, SOFTWARE_ENABLE | spurious_vector as u32);
+        self.write(TASK_PRIORITY_REGISTER, 0);
+        // the timer is not used yet
+        self.write(LVT_TIMER_REGISTER, LVT_MASKED);
+        self.write(LVT_ERROR_REGISTER, LVT_MASKED);
+    }
+
+    /// APIC ID, 8 bits in xAPIC mode and 32 bits in x2APIC mode
+    pub fn id(&self) -> u32 {
+        match self.mode {
+            Mode::XApic { .. } => self.read(ID_REGISTER) >> 24,
+            Mode::X2Apic => self.read(ID_REGISTER),
+        }
+    }
+
+    /// Deliver performance counter overflows as NMI. Every delivery masks
+    /// the entry, so this has to be called again by the NMI handler.
+    pub fn set_performance_counter_nmi(&self) {
+        self.write(LVT_PERFORMANCE_COUNTER_REGISTER, LVT_DELIVERY_NMI);
+    }
+
+    pub fn mask_performance_counter(&self) {
+        self.write(LVT_PERFORMANCE_COUNTER_REGISTER, LVT_MASKED);
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(EOI_REGISTER, 0);
+    }
+
+    /// Send `vector` as fixed interrupt to the CPU with APIC ID `destination`
+    pub fn send_ipi(&self, destination: u32, vector: u8) {
+        self.write_icr(destination, ICR_LEVEL_ASSERT | vector as u32);
+    }
+
+    /// Send `vector` as fixed interrupt to the current CPU
+    pub fn send_self_ipi(&self, vector: u8) {
+        match self.mode {
+            Mode::X2Apic => unsafe { cpu::write_msr(X2APIC_SELF_IPI_MSR, vector as u64) },
+            Mode::XApic { .. } => self.write_icr(0, ICR_DESTINATION_SELF | ICR_LEVEL_ASSERT | vector as u32),
+        }
+    }
+
+    fn write_icr(&self, destination: u32, command: u32) {
+        match self.mode {
+            // a single 64-bit write, the destination is in the upper half
+            Mode::X2Apic => unsafe {
+                cpu::write_msr(X2APIC_MSR_BASE + (ICR_LOW_REGISTER >> 4) as u32,
+                               (destination as u64) << 32 | command as u64);
+            },
+            Mode::XApic { .. } => {
+                // writing the low half sends the interrupt
+                self.write(ICR_HIGH_REGISTER, destination << 24);
+                self.write(ICR_LOW_REGISTER, command);
+                while self.read(ICR_LOW_REGISTER) & ICR_DELIVERY_PENDING != 0 {}
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Local APIC and I/O APIC. When they are present the ISA IRQs are routed
+//! through the I/O APICs, using the interrupt source overrides of the MADT,
+//! and the 8259 PICs stay masked.
+
+pub mod local_apic;
+pub mod io_apic;
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::vec::Vec;
+
+use spin::Once;
+
+use cpu;
+use sync::IrqMutex;
+use drivers::acpi;
+
+use self::local_apic::LocalApic;
+use self::io_apic::{IoApic, Redirection};
+
+/// Delivered by the local APIC for interrupts that went away before they were
+/// accepted, these must not get an end of interrupt
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const ISA_IRQ_COUNT: usize = 16;
+
+/// I/O APIC input and polarity and trigger mode of an ISA IRQ
+#[derive(Debug, Clone, Copy)]
+struct IsaRoute {
+    gsi: u32,
+    active_low: bool,
+    level_triggered: bool,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+static IO_APICS: IrqMutex<Option<Vec<IoApic>>> = IrqMutex::new(None);
+static ISA_ROUTES: Once<[Option<IsaRoute>; ISA_IRQ_COUNT]> = Once::new();
+
+/// Set up the local APIC and the I/O APICs if the system has them, with the
+/// ISA IRQs mapped to the vectors from `isa_vector_base` on, all masked.
+/// Returns whether the APICs are used.
+pub fn init(isa_vector_base: u8) -> bool {
+    if !cpu::has_apic() {
+        return false;
+    }
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return false,
+    };
+    if madt.io_apics.is_empty() {
+        return false;
+    }
+
+    let local_apic = LOCAL_APIC.call_once(|| LocalApic::new(madt.local_apic_address));
+    local_apic.init(SPURIOUS_VECTOR);
+
+    let io_apics: Vec<IoApic> = madt.io_apics.iter().map(|entry| {
+        IoApic::new(entry.id, entry.address, entry.gsi_base)
+    }).collect();
+    for io_apic in io_apics.iter() {
+        io_apic.mask_all();
+    }
+
+    // ISA IRQs are edge triggered and active high, unless overridden
+    let mut routes = [None; ISA_IRQ_COUNT];
+    for (irq, route) in routes.iter_mut().enumerate() {
+        *route = Some(IsaRoute { gsi: irq as u32, active_low: false, level_triggered: false });
+    }
+    let mut overridden = [false; ISA_IRQ_COUNT];
+    for interrupt_override in madt.overrides.iter().filter(|o| (o.irq as usize) < ISA_IRQ_COUNT) {
+        routes[interrupt_override.irq as usize] = Some(IsaRoute {
+            gsi: interrupt_override.gsi,
+            active_low: interrupt_override.active_low,
+            level_triggered: interrupt_override.level_triggered,
+        });
+        overridden[interrupt_override.irq as usize] = true;
+    }
+    // an IRQ whose input was taken over by another one is not connected,
+    // like IRQ 2 on most systems, where the timer uses input 2
+    for irq in 0..ISA_IRQ_COUNT {
+        let taken = madt.overrides.iter().any(|o| o.irq as usize != irq && o.gsi == irq as u32);
+        if taken && !overridden[irq] {
+            routes[irq] = None;
+        }
+    }
+
+    // I/O APIC destinations have 8 bits, higher x2APIC IDs would need interrupt remapping
+    let destination = local_apic.id() as u8;
+    for (irq, route) in routes.iter().enumerate() {
+        let route = match *route {
+            Some(route) => route,
+            None => continue,
+        };
+        if let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(route.gsi)) {
+            io_apic.set_redirection(route.gsi, Redirection {
+                vector: isa_vector_base + irq as u8,
+                destination: destination,
+                active_low: route.active_low,
+                level_triggered: route.level_triggered,
+                masked: true,
+            });
+        }
+    }
+
+    ISA_ROUTES.call_once(|| routes);
+    *IO_APICS.lock() = Some(io_apics);
+    ENABLED.store(true, Ordering::SeqCst);
+    true
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+pub fn local_apic() -> &'static LocalApic {
+    LOCAL_APIC.try().expect("local APIC not initialized")
+}
+
+fn set_isa_irq_masked(irq: u8, masked: bool) {
+    let route = match ISA_ROUTES.try().expect("I/O APIC not initialized")[irq as usize] {
+        Some(route) => route,
+        None => return,
+    };
+    if let Some(ref io_apics) = *IO_APICS.lock() {
+        if let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(route.gsi)) {
+            io_apic.set_masked(route.gsi, masked);
+        }
+    }
+}
+
+pub fn mask_isa_irq(irq: u8) {
+    set_isa_irq_masked(irq, true);
+}
+
+pub fn unmask_isa_irq(irq: u8) {
+    set_isa_irq_masked(irq, false);
+}
+
+pub fn end_of_interrupt() {
+    local_apic().end_of_interrupt();
+}
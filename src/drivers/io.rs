@@ -10,4 +10,14 @@ pub unsafe fn outb(port: u16, value: u8) {
 
 pub unsafe fn io_wait() {
     asm!("jmp 1f;1:jmp 2f;2:" :::: "volatile");
-}
\ No newline at end of file
+}
+
+pub unsafe fn inl(port: u16) -> u32 {
+    let result: u32;
+    asm!("inl %dx, %eax"  : "={eax}"(result) : "{dx}"(port) :: "volatile");
+    result
+}
+
+pub unsafe fn outl(port: u16, value: u32) {
+    asm!("outl %eax, %dx" :: "{dx}"(port), "{eax}"(value) :: "volatile");
+}
@@ -3,13 +3,15 @@ pub mod video;
 
 pub mod io;
 pub mod pic;
+pub mod acpi;
+pub mod apic;
+pub mod pci;
 pub mod keyboard;
 
 #[macro_use]
 pub mod serial;
 
 pub fn configure() {
-    pic::MASTER.init(0x20, true);
-    pic::SLAVE.init(0x28, false);
+    pic::PICS.init();
 
 }
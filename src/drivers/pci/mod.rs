@@ -0,0 +1,181 @@
+//! PCI configuration space through the I/O ports of configuration mechanism 1,
+//! enough to find devices and program their capabilities.
+
+pub mod msi;
+
+use alloc::vec::Vec;
+
+use sync::IrqMutex;
+
+use drivers::io::{inl, outl};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+const VENDOR_ID: u8 = 0x00;
+const DEVICE_ID: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+const STATUS: u8 = 0x06;
+const CLASS: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0e;
+const BAR0: u8 = 0x10;
+const CAPABILITIES_POINTER: u8 = 0x34;
+
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// Disables the legacy INTx pin interrupts
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+/// Read from the vendor ID register when there is no device
+const NO_VENDOR: u16 = 0xffff;
+
+/// Writing the address and accessing the data are two steps
+static CONFIG_LOCK: IrqMutex<()> = IrqMutex::new(());
+
+/// A function of a device on the PCI bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciDevice {
+    fn config_address(&self, offset: u8) -> u32 {
+        CONFIG_ENABLE | (self.bus as u32) << 16 | (self.device as u32) << 11 |
+            (self.function as u32) << 8 | (offset & 0xfc) as u32
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        let _lock = CONFIG_LOCK.lock();
+        unsafe {
+            outl(CONFIG_ADDRESS, self.config_address(offset));
+            inl(CONFIG_DATA)
+        }
+    }
+
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        let _lock = CONFIG_LOCK.lock();
+        unsafe {
+            outl(CONFIG_ADDRESS, self.config_address(offset));
+            outl(CONFIG_DATA, value);
+        }
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 0b10) * 8)) as u16
+    }
+
+    /// Writes the whole dword, so the other half must not have side effects
+    /// when written back, like the write-one-to-clear bits of the status register
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 0b10) * 8;
+        let dword = self.read_u32(offset) & !(0xffff << shift) | (value as u32) << shift;
+        self.write_u32(offset, dword);
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 0b11) * 8)) as u8
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(DEVICE_ID)
+    }
+
+    /// Class, subclass and programming interface
+    pub fn class(&self) -> (u8, u8, u8) {
+        let class = self.read_u32(CLASS);
+        ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8)
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(COMMAND)
+    }
+
+    /// The status register is in the same dword, its bits are cleared by writing ones
+    pub fn set_command(&self, command: u16) {
+        self.write_u32(COMMAND, command as u32);
+    }
+
+    /// Physical address of memory BAR `index`, None for I/O BARs
+    pub fn memory_bar(&self, index: u8) -> Option<usize> {
+        assert!(index < 6, "invalid BAR {}", index);
+        let low = self.read_u32(BAR0 + index * 4);
+        if low & 1 != 0 {
+            return None;
+        }
+        let address = (low & !0xf) as usize;
+        // 64-bit BARs continue in the next one
+        if (low >> 1) & 0b11 == 0b10 {
+            Some(address | (self.read_u32(BAR0 + (index + 1) * 4) as usize) << 32)
+        } else {
+            Some(address)
+        }
+    }
+
+    /// Offsets of the capabilities
+    pub fn capabilities(&self) -> Capabilities {
+        let next = if self.read_u16(STATUS) & STATUS_CAPABILITIES_LIST != 0 {
+            self.read_u8(CAPABILITIES_POINTER) & 0xfc
+        } else {
+            0
+        };
+        Capabilities { device: *self, next: next }
+    }
+
+    /// Offset of the first capability with `id`
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities().find(|&offset| self.read_u8(offset) == id)
+    }
+}
+
+/// Iterator over the linked list of capabilities of a device
+pub struct Capabilities {
+    device: PciDevice,
+    next: u8,
+}
+
+impl Iterator for Capabilities {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.next == 0 {
+            return None;
+        }
+        let offset = self.next;
+        self.next = self.device.read_u8(offset + 1) & 0xfc;
+        Some(offset)
+    }
+}
+
+fn exists(device: PciDevice) -> bool {
+    device.vendor_id() != NO_VENDOR
+}
+
+/// Scan all buses for devices
+pub fn devices() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..256 {
+        for device in 0..32 {
+            let first = PciDevice { bus: bus as u8, device: device, function: 0 };
+            if !exists(first) {
+                continue;
+            }
+            let functions = if first.read_u8(HEADER_TYPE) & HEADER_TYPE_MULTI_FUNCTION != 0 { 8 } else { 1 };
+            for function in 0..functions {
+                let candidate = PciDevice { bus: bus as u8, device: device, function: function };
+                if exists(candidate) {
+                    devices.push(candidate);
+                }
+            }
+        }
+    }
+    devices
+}
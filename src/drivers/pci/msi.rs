@@ -0,0 +1,225 @@
+//! Message signaled interrupts. The device writes the vector to the local
+//! APIC of the destination CPU, so the I/O APIC is not involved. MSI-X is
+//! preferred, it has a table entry with its own destination per vector,
+//! while all MSI vectors go to the same CPU.
+
+use core::ptr;
+
+use alloc::vec::Vec;
+
+use drivers::apic;
+use interrupts::irq;
+use memory::physical_map::map_mmio;
+
+use super::{PciDevice, COMMAND_BUS_MASTER, COMMAND_INTX_DISABLE, COMMAND_MEMORY_SPACE};
+
+const MSI_CAPABILITY: u8 = 0x05;
+const MSIX_CAPABILITY: u8 = 0x11;
+
+// offsets from the start of the capabilities
+const MESSAGE_CONTROL: u8 = 0x02;
+const MSI_ADDRESS: u8 = 0x04;
+const MSI_ADDRESS_HIGH: u8 = 0x08;
+/// The data follows the address, which has 32 or 64 bits
+const MSI_DATA_32: u8 = 0x08;
+const MSI_DATA_64: u8 = 0x0c;
+const MSIX_TABLE: u8 = 0x04;
+
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_64_BIT: u16 = 1 << 7;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+
+// fields of an MSI-X table entry
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS: usize = 0x0;
+const MSIX_ENTRY_ADDRESS_HIGH: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_CONTROL: usize = 0xc;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// Writes to this range are interrupt messages for the local APICs
+const MESSAGE_ADDRESS_BASE: u32 = 0xfee0_0000;
+/// Without interrupt remapping messages can only name 8-bit APIC IDs
+const MAX_DESTINATION: u32 = 0xff;
+
+enum Kind {
+    /// `block` vectors are allocated, the enabled count rounded up to a power of two
+    Msi { capability: u8, block: usize },
+    /// Table mapped at `table`
+    MsiX { capability: u8, table: usize },
+}
+
+/// Vectors allocated to a device and the capability delivering them.
+/// Dropping it, when the driver is removed, disables the messages and
+/// frees the vectors with their handlers.
+pub struct MsiVectors {
+    device: PciDevice,
+    kind: Kind,
+    vectors: Vec<u8>,
+}
+
+impl MsiVectors {
+    pub fn device(&self) -> PciDevice {
+        self.device
+    }
+
+    /// Vector of message `index`, handlers are added with `irq::register_vector`
+    pub fn vectors(&self) -> &[u8] {
+        &self.vectors
+    }
+
+    pub fn is_msix(&self) -> bool {
+        match self.kind {
+            Kind::MsiX { .. } => true,
+            Kind::Msi { .. } => false,
+        }
+    }
+}
+
+impl Drop for MsiVectors {
+    fn drop(&mut self) {
+        let device = self.device;
+        match self.kind {
+            Kind::Msi { capability, block } => {
+                let control = device.read_u16(capability + MESSAGE_CONTROL);
+                device.write_u16(capability + MESSAGE_CONTROL, control & !MSI_ENABLE);
+                irq::free_vectors(self.vectors[0], block);
+            }
+            Kind::MsiX { capability, table } => {
+                for index in 0..self.vectors.len() {
+                    write_entry(table, index, MSIX_ENTRY_CONTROL, MSIX_ENTRY_MASKED);
+                }
+                let control = device.read_u16(capability + MESSAGE_CONTROL);
+                device.write_u16(capability + MESSAGE_CONTROL, control & !MSIX_ENABLE);
+                for &vector in self.vectors.iter() {
+                    irq::free_vectors(vector, 1);
+                }
+            }
+        }
+        device.set_command(device.command() & !COMMAND_INTX_DISABLE);
+    }
+}
+
+fn message_address(destination: u32) -> u32 {
+    MESSAGE_ADDRESS_BASE | destination << 12
+}
+
+fn write_entry(table: usize, index: usize, field: usize, value: u32) {
+    unsafe { ptr::write_volatile((table + index * MSIX_ENTRY_SIZE + field) as *mut u32, value); }
+}
+
+/// Allocate `count` vectors for `device` and program its MSI-X or MSI capability
+/// to send them, message `i` to the CPU with APIC ID `destinations[i % destinations.len()]`.
+/// With MSI only the first destination is used. The vectors are returned unhandled,
+/// the messages are enabled and the legacy interrupt pin is disabled.
+/// Returns None if the device supports neither for `count` vectors, the vectors ran
+/// out or the APIC is not used.
+pub fn request_vectors(device: PciDevice, count: usize, destinations: &[u32]) -> Option<MsiVectors> {
+    assert!(count > 0 && !destinations.is_empty(), "request_vectors: no vectors or destinations");
+    assert!(destinations.iter().all(|&destination| destination <= MAX_DESTINATION),
+            "request_vectors: APIC ID out of range for MSI");
+    if !apic::enabled() {
+        return None;
+    }
+
+    let vectors = match device.find_capability(MSIX_CAPABILITY) {
+        Some(capability) => enable_msix(device, capability, count, destinations),
+        None => None,
+    };
+    let vectors = match vectors {
+        Some(vectors) => Some(vectors),
+        None => match device.find_capability(MSI_CAPABILITY) {
+            Some(capability) => enable_msi(device, capability, count, destinations[0]),
+            None => None,
+        },
+    };
+
+    if vectors.is_some() {
+        device.set_command(device.command() | COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE);
+    }
+    vectors
+}
+
+fn enable_msix(device: PciDevice, capability: u8, count: usize, destinations: &[u32]) -> Option<MsiVectors> {
+    let control = device.read_u16(capability + MESSAGE_CONTROL);
+    let table_size = (control & 0x7ff) as usize + 1;
+    if count > table_size {
+        return None;
+    }
+
+    // the low bits of the offset select the BAR
+    let table_location = device.read_u32(capability + MSIX_TABLE);
+    let table_address = match device.memory_bar((table_location & 0b111) as u8) {
+        Some(address) => address + (table_location & !0b111) as usize,
+        None => return None,
+    };
+
+    let mut vectors = Vec::with_capacity(count);
+    for _ in 0..count {
+        match irq::allocate_vectors(1, 1) {
+            Some(vector) => vectors.push(vector),
+            None => {
+                for &vector in vectors.iter() {
+                    irq::free_vectors(vector, 1);
+                }
+                return None;
+            }
+        }
+    }
+
+    let table = map_mmio(table_address, table_size * MSIX_ENTRY_SIZE);
+    device.set_command(device.command() | COMMAND_MEMORY_SPACE);
+    // no message is sent while the entries are changed
+    device.write_u16(capability + MESSAGE_CONTROL, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+    for index in 0..table_size {
+        write_entry(table, index, MSIX_ENTRY_CONTROL, MSIX_ENTRY_MASKED);
+    }
+    for (index, &vector) in vectors.iter().enumerate() {
+        let destination = destinations[index % destinations.len()];
+        write_entry(table, index, MSIX_ENTRY_ADDRESS, message_address(destination));
+        write_entry(table, index, MSIX_ENTRY_ADDRESS_HIGH, 0);
+        write_entry(table, index, MSIX_ENTRY_DATA, vector as u32);
+        write_entry(table, index, MSIX_ENTRY_CONTROL, 0);
+    }
+    device.write_u16(capability + MESSAGE_CONTROL, (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+
+    Some(MsiVectors {
+        device: device,
+        kind: Kind::MsiX { capability: capability, table: table },
+        vectors: vectors,
+    })
+}
+
+fn enable_msi(device: PciDevice, capability: u8, count: usize, destination: u32) -> Option<MsiVectors> {
+    let control = device.read_u16(capability + MESSAGE_CONTROL);
+    // the device sets the low bits of the data to the message number, so the
+    // vectors are a block aligned to its power of two size
+    let block = count.next_power_of_two();
+    let capable = 1 << ((control >> 1) & 0b111);
+    if block > capable {
+        return None;
+    }
+    let first = match irq::allocate_vectors(block, block) {
+        Some(first) => first,
+        None => return None,
+    };
+
+    let data_offset = if control & MSI_64_BIT != 0 {
+        device.write_u32(capability + MSI_ADDRESS_HIGH, 0);
+        capability + MSI_DATA_64
+    } else {
+        capability + MSI_DATA_32
+    };
+    device.write_u32(capability + MSI_ADDRESS, message_address(destination));
+    device.write_u16(data_offset, first as u16);
+
+    let enabled = (block.trailing_zeros() as u16) << 4;
+    device.write_u16(capability + MESSAGE_CONTROL, control & !(0b111 << 4) | enabled | MSI_ENABLE);
+
+    Some(MsiVectors {
+        device: device,
+        kind: Kind::Msi { capability: capability, block: block },
+        vectors: (0..count).map(|i| first + i as u8).collect(),
+    })
+}
@@ -1,25 +1,32 @@
 use super::io::*;
 
-pub const MASTER: Pic = Pic::new(0x20, 0x21);
-pub const SLAVE: Pic = Pic::new(0xA0, 0xA1);
+/// The two 8259 PICs of a PC, IRQs 0-7 go to the master, 8-15 to the slave
+pub static PICS: ChainedPics = ChainedPics::new(0x20, 0x28);
 
 const EOI_COMMAND: u8 = 0x20;
 const INIT_COMMAND: u8 = 0x11;
 const MODE_8086: u8 = 0x01;
+/// OCW3 selecting the in-service register for the next read of the command port
+const READ_ISR_COMMAND: u8 = 0x0b;
 
-pub struct Pic {
+/// Master line the slave is connected to
+const CASCADE_IRQ: u8 = 2;
+/// Lowest priority line of each PIC, where spurious interrupts show up
+const SPURIOUS_LINE: u8 = 7;
+
+struct Pic {
+    offset: u8,
     command_port: u16,
     data_port: u16
 }
 
 impl Pic {
-    pub const fn new(command_port: u16, data_port: u16) -> Self {
-        Pic { command_port: command_port, data_port: data_port }
+    const fn new(offset: u8, command_port: u16, data_port: u16) -> Self {
+        Pic { offset: offset, command_port: command_port, data_port: data_port }
     }
 
     // Enable irq of a certain pic
-    pub fn enable_irq(&self, irq_line: u8) {
-        let irq_line = irq_line % 8;
+    fn enable_irq(&self, irq_line: u8) {
         unsafe {
             let value = inb(self.data_port) & !(1 << irq_line);
             outb(self.data_port, value);
@@ -27,22 +34,33 @@ impl Pic {
     }
 
     // Disable irq of a certain pic
-    pub fn disable_irq(&self, irq_line: u8) {
-        let irq_line = irq_line % 8;
+    fn disable_irq(&self, irq_line: u8) {
         unsafe {
             let value = inb(self.data_port) | (1 << irq_line);
             outb(self.data_port, value);
         }
     }
 
+    fn is_masked(&self, irq_line: u8) -> bool {
+        unsafe { inb(self.data_port) & (1 << irq_line) != 0 }
+    }
+
     // Send end of interrupt command, needed to continue receiving more irqs.
-    pub fn send_eoi(&self) {
+    fn send_eoi(&self) {
         unsafe {
             outb(self.command_port, EOI_COMMAND);
         }
     }
 
-    pub fn init(&self, offset: u8, is_master: bool) {
+    /// Lines that are being serviced, waiting for an end of interrupt
+    fn in_service(&self) -> u8 {
+        unsafe {
+            outb(self.command_port, READ_ISR_COMMAND);
+            inb(self.command_port)
+        }
+    }
+
+    fn init(&self, is_master: bool) {
         unsafe {
             // Save mask
             let mask = inb(self.data_port);
@@ -50,16 +68,119 @@ impl Pic {
             outb(self.command_port, INIT_COMMAND);
             io_wait();
             //PIC vector offset
-            outb(self.data_port, offset);
+            outb(self.data_port, self.offset);
             io_wait();
             // Tell master PIC that there is a slave PIC at IRQ 2
-            outb(self.data_port, if is_master { 4 } else { 2 });
+            outb(self.data_port, if is_master { 1 << CASCADE_IRQ } else { CASCADE_IRQ });
             io_wait();
             // Set mode
             outb(self.data_port, MODE_8086);
             io_wait();
             // Restore mask
-            outb(self.data_port, mask); 
+            outb(self.data_port, mask);
+        }
+    }
+}
+
+/// Master and slave PIC, addressed with IRQ numbers from 0 to 15
+pub struct ChainedPics {
+    master: Pic,
+    slave: Pic,
+}
+
+impl ChainedPics {
+    pub const fn new(master_offset: u8, slave_offset: u8) -> ChainedPics {
+        ChainedPics {
+            master: Pic::new(master_offset, 0x20, 0x21),
+            slave: Pic::new(slave_offset, 0xA0, 0xA1),
+        }
+    }
+
+    /// Remap both PICs to their offsets, keeping the masks
+    pub fn init(&self) {
+        self.master.init(true);
+        self.slave.init(false);
+    }
+
+    /// Vector the IRQ is delivered on
+    pub fn vector(&self, irq: u8) -> u8 {
+        assert!(irq < 16, "pic: invalid irq {}", irq);
+        if irq < 8 {
+            self.master.offset + irq
+        } else {
+            self.slave.offset + irq - 8
         }
     }
-}
\ No newline at end of file
+
+    /// IRQ delivered on `vector`, if it belongs to one of the PICs
+    pub fn irq(&self, vector: u8) -> Option<u8> {
+        if vector >= self.master.offset && vector < self.master.offset + 8 {
+            Some(vector - self.master.offset)
+        } else if vector >= self.slave.offset && vector < self.slave.offset + 8 {
+            Some(vector - self.slave.offset + 8)
+        } else {
+            None
+        }
+    }
+
+    /// Unmask `irq`, lines of the slave also need the cascade line of the master
+    pub fn unmask(&self, irq: u8) {
+        assert!(irq < 16, "pic: invalid irq {}", irq);
+        if irq < 8 {
+            self.master.enable_irq(irq);
+        } else {
+            self.slave.enable_irq(irq - 8);
+            self.master.enable_irq(CASCADE_IRQ);
+        }
+    }
+
+    pub fn mask(&self, irq: u8) {
+        assert!(irq < 16, "pic: invalid irq {}", irq);
+        if irq < 8 {
+            self.master.disable_irq(irq);
+        } else {
+            self.slave.disable_irq(irq - 8);
+        }
+    }
+
+    pub fn is_masked(&self, irq: u8) -> bool {
+        assert!(irq < 16, "pic: invalid irq {}", irq);
+        if irq < 8 {
+            self.master.is_masked(irq)
+        } else {
+            self.slave.is_masked(irq - 8)
+        }
+    }
+
+    /// In-service registers of both PICs, the slave in the upper byte
+    pub fn in_service(&self) -> u16 {
+        (self.slave.in_service() as u16) << 8 | self.master.in_service() as u16
+    }
+
+    /// Check whether `irq` is a spurious IRQ 7 or 15, raised by a PIC for a request
+    /// that went away before it was acknowledged. The PIC does not mark those as
+    /// in service, so they must not get an end of interrupt. For a spurious IRQ 15
+    /// the master did see a real request on the cascade line, it gets an EOI here.
+    pub fn is_spurious(&self, irq: u8) -> bool {
+        if irq == SPURIOUS_LINE {
+            self.master.in_service() & (1 << SPURIOUS_LINE) == 0
+        } else if irq == 8 + SPURIOUS_LINE {
+            let spurious = self.slave.in_service() & (1 << SPURIOUS_LINE) == 0;
+            if spurious {
+                self.master.send_eoi();
+            }
+            spurious
+        } else {
+            false
+        }
+    }
+
+    /// Signal the end of `irq`, the slave and the master both need one for IRQs 8-15
+    pub fn end_of_interrupt(&self, irq: u8) {
+        assert!(irq < 16, "pic: invalid irq {}", irq);
+        if irq >= 8 {
+            self.slave.send_eoi();
+        }
+        self.master.send_eoi();
+    }
+}
@@ -1,13 +1,13 @@
 use uart_16550::SerialPort;
-use spin::Mutex;
+use sync::IrqMutex;
 use core::fmt::Write;
 use core::fmt::Arguments;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqMutex<SerialPort> = {
         let mut serial_port = SerialPort::new(0x3F8);
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqMutex::new(serial_port)
     };
 }
 
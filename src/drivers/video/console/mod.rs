@@ -1,13 +1,13 @@
 use core::fmt;
 use core::fmt::Write;
 
-use spin::Mutex;
+use sync::IrqMutex;
 
 pub mod vga_console;
 
 use self::vga_console::{VgaConsole, Buffer};
 
-pub static WRITER: Mutex<VgaConsole> = Mutex::new(VgaConsole::new(0xb8000 as *mut Buffer));
+pub static WRITER: IrqMutex<VgaConsole> = IrqMutex::new(VgaConsole::new(0xb8000 as *mut Buffer));
 
 #[macro_export]
 macro_rules! print {
@@ -0,0 +1,202 @@
+//! Work deferred by interrupt handlers. Tasklets are short jobs that run
+//! with interrupts enabled right after the handler that scheduled them
+//! returned, still on the interrupted stack. They must not take plain spin
+//! locks the interrupted code could hold, only `IrqMutex`es. Longer jobs and
+//! jobs taking such locks go to the work queue, which is run by the main loop.
+//! Both queues have a fixed size, so scheduling never allocates.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64;
+
+use sync::IrqMutex;
+
+const QUEUE_SIZE: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Work {
+    function: fn(usize),
+    argument: usize,
+}
+
+struct Queue {
+    items: [Option<Work>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Queue {
+    const fn new() -> Queue {
+        Queue { items: [None; QUEUE_SIZE], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, work: Work) -> bool {
+        if self.len == QUEUE_SIZE {
+            return false;
+        }
+        self.items[(self.head + self.len) % QUEUE_SIZE] = Some(work);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<Work> {
+        if self.len == 0 {
+            return None;
+        }
+        let work = self.items[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        work
+    }
+}
+
+static TASKLETS: IrqMutex<Queue> = IrqMutex::new(Queue::new());
+static WORK_QUEUE: IrqMutex<Queue> = IrqMutex::new(Queue::new());
+
+static RUNNING_TASKLETS: AtomicBool = AtomicBool::new(false);
+/// Jobs that did not fit into their queue
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+fn push(queue: &IrqMutex<Queue>, work: Work) -> bool {
+    let pushed = queue.lock().push(work);
+    if !pushed {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    pushed
+}
+
+fn pop(queue: &IrqMutex<Queue>) -> Option<Work> {
+    queue.lock().pop()
+}
+
+/// A job run once after an interrupt handler scheduled it, however often
+/// it was scheduled in the meantime
+pub struct Tasklet {
+    function: fn(),
+    scheduled: AtomicBool,
+}
+
+impl Tasklet {
+    pub const fn new(function: fn()) -> Tasklet {
+        Tasklet { function: function, scheduled: AtomicBool::new(false) }
+    }
+
+    /// Returns false if the tasklet queue is full
+    pub fn schedule(&'static self) -> bool {
+        if self.scheduled.swap(true, Ordering::AcqRel) {
+            return true;
+        }
+        let pushed = push(&TASKLETS, Work { function: run_tasklet, argument: self as *const Tasklet as usize });
+        if !pushed {
+            self.scheduled.store(false, Ordering::Release);
+        }
+        pushed
+    }
+}
+
+fn run_tasklet(tasklet: usize) {
+    let tasklet = unsafe { &*(tasklet as *const Tasklet) };
+    // cleared first, so it can be scheduled again while it runs
+    tasklet.scheduled.store(false, Ordering::Release);
+    (tasklet.function)();
+}
+
+/// Run `function(argument)` from the main loop.
+/// Returns false if the work queue is full.
+pub fn queue_work(function: fn(usize), argument: usize) -> bool {
+    push(&WORK_QUEUE, Work { function: function, argument: argument })
+}
+
+/// Run the scheduled tasklets with interrupts enabled. Called at the end of
+/// interrupt dispatch with interrupts disabled, and returns with them disabled.
+/// Interrupts arriving meanwhile leave their tasklets to the running loop.
+pub fn run_tasklets() {
+    if RUNNING_TASKLETS.swap(true, Ordering::Acquire) {
+        return;
+    }
+    loop {
+        x86_64::instructions::interrupts::enable();
+        while let Some(work) = pop(&TASKLETS) {
+            (work.function)(work.argument);
+        }
+        x86_64::instructions::interrupts::disable();
+        // nothing can be scheduled between this check and clearing the flag
+        if TASKLETS.lock().len == 0 {
+            break;
+        }
+    }
+    RUNNING_TASKLETS.store(false, Ordering::Release);
+}
+
+/// Run the queued work, called from the main loop
+pub fn run_work() {
+    while let Some(work) = pop(&WORK_QUEUE) {
+        (work.function)(work.argument);
+    }
+}
+
+/// Tasklets and work items dropped because their queue was full
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nothing(_: usize) {}
+
+    fn work(argument: usize) -> Work {
+        Work { function: nothing, argument: argument }
+    }
+
+    fn pop_argument(queue: &mut Queue) -> Option<usize> {
+        queue.pop().map(|work| work.argument)
+    }
+
+    #[test]
+    fn full_queue() {
+        let mut queue = Queue::new();
+        for i in 0..QUEUE_SIZE {
+            assert!(queue.push(work(i)));
+        }
+        assert!(!queue.push(work(QUEUE_SIZE)));
+        assert_eq!(queue.len, QUEUE_SIZE);
+
+        for i in 0..QUEUE_SIZE {
+            assert_eq!(pop_argument(&mut queue), Some(i));
+        }
+        assert_eq!(pop_argument(&mut queue), None);
+        assert!(queue.push(work(0)));
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut queue = Queue::new();
+        for i in 0..QUEUE_SIZE - 1 {
+            assert!(queue.push(work(i)));
+            assert_eq!(pop_argument(&mut queue), Some(i));
+        }
+        assert_eq!(queue.head, QUEUE_SIZE - 1);
+
+        // the second and third item go to the start of the array
+        for i in 0..3 {
+            assert!(queue.push(work(i)));
+        }
+        assert!(queue.items[0].is_some() && queue.items[1].is_some());
+        for i in 0..3 {
+            assert_eq!(pop_argument(&mut queue), Some(i));
+        }
+        assert_eq!(pop_argument(&mut queue), None);
+        assert_eq!(queue.head, 2);
+
+        // filled up across the end of the array
+        for i in 0..QUEUE_SIZE {
+            assert!(queue.push(work(i)));
+        }
+        assert!(!queue.push(work(QUEUE_SIZE)));
+        for i in 0..QUEUE_SIZE {
+            assert_eq!(pop_argument(&mut queue), Some(i));
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Handlers for the architectural exceptions. The vectors that are obsolete,
+//! reserved by Intel or only defined by AMD (9, 15, 21-29 and 31) have no
+//! field in `InterruptDescriptorTable`, their gates are written through the
+//! raw entry array. Non-maskable interrupts and machine checks have their own handlers in
+//! `nmi` and `machine_check`.
+//! Every report is written to the screen and to the serial port, if their
+//! locks are free.
+
+use core::mem;
+
+use x86_64::structures::idt::{InterruptDescriptorTable, Entry, HandlerFunc};
+
+use cpu;
+use memory;
+
+use super::InterruptContext;
+use super::ist;
+use super::{DOUBLE_FAULT_IST, PAGE_FAULT_IST, DEBUG_IST};
+
+const DIVIDE_ERROR: u64 = 0;
+const DEBUG: u64 = 1;
+const NON_MASKABLE_INTERRUPT: u64 = 2;
+const BREAKPOINT: u64 = 3;
+const OVERFLOW: u64 = 4;
+const BOUND_RANGE_EXCEEDED: u64 = 5;
+const INVALID_OPCODE: u64 = 6;
+const DEVICE_NOT_AVAILABLE: u64 = 7;
+const DOUBLE_FAULT: u64 = 8;
+const COPROCESSOR_SEGMENT_OVERRUN: u64 = 9;
+const INVALID_TSS: u64 = 10;
+const SEGMENT_NOT_PRESENT: u64 = 11;
+const STACK_SEGMENT_FAULT: u64 = 12;
+const GENERAL_PROTECTION_FAULT: u64 = 13;
+const PAGE_FAULT: u64 = 14;
+const RESERVED_15: u64 = 15;
+const X87_FLOATING_POINT: u64 = 16;
+const ALIGNMENT_CHECK: u64 = 17;
+const MACHINE_CHECK: u64 = 18;
+const SIMD_FLOATING_POINT: u64 = 19;
+const VIRTUALIZATION: u64 = 20;
+const CONTROL_PROTECTION: u64 = 21;
+const RESERVED_22: u64 = 22;
+const RESERVED_23: u64 = 23;
+const RESERVED_24: u64 = 24;
+const RESERVED_25: u64 = 25;
+const RESERVED_26: u64 = 26;
+const RESERVED_27: u64 = 27;
+const HYPERVISOR_INJECTION: u64 = 28;
+const VMM_COMMUNICATION: u64 = 29;
+const SECURITY_EXCEPTION: u64 = 30;
+const RESERVED_31: u64 = 31;
+
+/// Print to the screen and the serial port. The exception may have
+/// interrupted the owner of their locks, see `nmi::report_unlocked`.
+macro_rules! report {
+    ($($arg:tt)*) => ({
+        super::nmi::report_unlocked(format_args!($($arg)*));
+    });
+}
+
+interrupt_stub!(divide_error_stub, DIVIDE_ERROR, exception_handler);
+interrupt_stub!(debug_stub, DEBUG, exception_handler);
+interrupt_stub!(breakpoint_stub, BREAKPOINT, exception_handler);
+interrupt_stub!(overflow_stub, OVERFLOW, exception_handler);
+interrupt_stub!(bound_range_exceeded_stub, BOUND_RANGE_EXCEEDED, exception_handler);
+interrupt_stub!(invalid_opcode_stub, INVALID_OPCODE, exception_handler);
+interrupt_stub!(device_not_available_stub, DEVICE_NOT_AVAILABLE, exception_handler);
+interrupt_stub!(double_fault_stub, DOUBLE_FAULT, exception_handler, error_code);
+interrupt_stub!(invalid_tss_stub, INVALID_TSS, exception_handler, error_code);
+interrupt_stub!(segment_not_present_stub, SEGMENT_NOT_PRESENT, exception_handler, error_code);
+interrupt_stub!(stack_segment_fault_stub, STACK_SEGMENT_FAULT, exception_handler, error_code);
+interrupt_stub!(general_protection_fault_stub, GENERAL_PROTECTION_FAULT, exception_handler, error_code);
+interrupt_stub!(page_fault_stub, PAGE_FAULT, exception_handler, error_code);
+interrupt_stub!(x87_floating_point_stub, X87_FLOATING_POINT, exception_handler);
+interrupt_stub!(alignment_check_stub, ALIGNMENT_CHECK, exception_handler, error_code);
+interrupt_stub!(simd_floating_point_stub, SIMD_FLOATING_POINT, exception_handler);
+interrupt_stub!(virtualization_stub, VIRTUALIZATION, exception_handler);
+interrupt_stub!(security_exception_stub, SECURITY_EXCEPTION, exception_handler, error_code);
+interrupt_stub!(coprocessor_segment_overrun_stub, COPROCESSOR_SEGMENT_OVERRUN, exception_handler);
+interrupt_stub!(reserved_15_stub, RESERVED_15, exception_handler);
+interrupt_stub!(control_protection_stub, CONTROL_PROTECTION, exception_handler, error_code);
+interrupt_stub!(reserved_22_stub, RESERVED_22, exception_handler);
+interrupt_stub!(reserved_23_stub, RESERVED_23, exception_handler);
+interrupt_stub!(reserved_24_stub, RESERVED_24, exception_handler);
+interrupt_stub!(reserved_25_stub, RESERVED_25, exception_handler);
+interrupt_stub!(reserved_26_stub, RESERVED_26, exception_handler);
+interrupt_stub!(reserved_27_stub, RESERVED_27, exception_handler);
+interrupt_stub!(hypervisor_injection_stub, HYPERVISOR_INJECTION, exception_handler);
+interrupt_stub!(vmm_communication_stub, VMM_COMMUNICATION, exception_handler, error_code);
+interrupt_stub!(reserved_31_stub, RESERVED_31, exception_handler);
+
+/// Stubs of the vectors without a field in `InterruptDescriptorTable`
+const UNNAMED_STUBS: [(u64, unsafe extern "C" fn()); 12] = [
+    (COPROCESSOR_SEGMENT_OVERRUN, coprocessor_segment_overrun_stub),
+    (RESERVED_15, reserved_15_stub),
+    (CONTROL_PROTECTION, control_protection_stub),
+    (RESERVED_22, reserved_22_stub),
+    (RESERVED_23, reserved_23_stub),
+    (RESERVED_24, reserved_24_stub),
+    (RESERVED_25, reserved_25_stub),
+    (RESERVED_26, reserved_26_stub),
+    (RESERVED_27, reserved_27_stub),
+    (HYPERVISOR_INJECTION, hypervisor_injection_stub),
+    (VMM_COMMUNICATION, vmm_communication_stub),
+    (RESERVED_31, reserved_31_stub),
+];
+
+/// Point all exception entries of `idt` to their stubs
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    // the stubs take care of the interrupt calling convention themselves,
+    // the entries only need their addresses
+    unsafe {
+        idt.divide_by_zero.set_handler_fn(mem::transmute(divide_error_stub as unsafe extern "C" fn()));
+        idt.debug.set_handler_fn(mem::transmute(debug_stub as unsafe extern "C" fn()))
+                 .set_stack_index(ist::stack_index(DEBUG_IST));
+        idt.breakpoint.set_handler_fn(mem::transmute(breakpoint_stub as unsafe extern "C" fn()));
+        idt.overflow.set_handler_fn(mem::transmute(overflow_stub as unsafe extern "C" fn()));
+        idt.bound_range_exceeded.set_handler_fn(mem::transmute(bound_range_exceeded_stub as unsafe extern "C" fn()));
+        idt.invalid_opcode.set_handler_fn(mem::transmute(invalid_opcode_stub as unsafe extern "C" fn()));
+        idt.device_not_available.set_handler_fn(mem::transmute(device_not_available_stub as unsafe extern "C" fn()));
+        idt.double_fault.set_handler_fn(mem::transmute(double_fault_stub as unsafe extern "C" fn()))
+                        .set_stack_index(ist::stack_index(DOUBLE_FAULT_IST));
+        idt.invalid_tss.set_handler_fn(mem::transmute(invalid_tss_stub as unsafe extern "C" fn()));
+        idt.segment_not_present.set_handler_fn(mem::transmute(segment_not_present_stub as unsafe extern "C" fn()));
+        idt.stack_segment_fault.set_handler_fn(mem::transmute(stack_segment_fault_stub as unsafe extern "C" fn()));
+        idt.general_protection_fault.set_handler_fn(mem::transmute(general_protection_fault_stub as unsafe extern "C" fn()));
+        idt.page_fault.set_handler_fn(mem::transmute(page_fault_stub as unsafe extern "C" fn()))
+                      .set_stack_index(ist::stack_index(PAGE_FAULT_IST));
+        idt.x87_floating_point.set_handler_fn(mem::transmute(x87_floating_point_stub as unsafe extern "C" fn()));
+        idt.alignment_check.set_handler_fn(mem::transmute(alignment_check_stub as unsafe extern "C" fn()));
+        idt.simd_floating_point.set_handler_fn(mem::transmute(simd_floating_point_stub as unsafe extern "C" fn()));
+        idt.virtualization.set_handler_fn(mem::transmute(virtualization_stub as unsafe extern "C" fn()));
+        idt.security_exception.set_handler_fn(mem::transmute(security_exception_stub as unsafe extern "C" fn()));
+
+        // the table is an array of 256 gates of the same layout, the named
+        // fields are only views of the first 32
+        let entries = &mut *(idt as *mut InterruptDescriptorTable as *mut [Entry<HandlerFunc>; 256]);
+        for &(vector, stub) in UNNAMED_STUBS.iter() {
+            entries[vector as usize].set_handler_fn(mem::transmute(stub));
+        }
+    }
+}
+
+fn name(vector: u64) -> &'static str {
+    match vector {
+        DIVIDE_ERROR => "DIVIDE ERROR",
+        DEBUG => "DEBUG",
+        NON_MASKABLE_INTERRUPT => "NON MASKABLE INTERRUPT",
+        BREAKPOINT => "BREAKPOINT",
+        OVERFLOW => "OVERFLOW",
+        BOUND_RANGE_EXCEEDED => "BOUND RANGE EXCEEDED",
+        INVALID_OPCODE => "INVALID OPCODE",
+        DEVICE_NOT_AVAILABLE => "DEVICE NOT AVAILABLE",
+        DOUBLE_FAULT => "DOUBLE FAULT",
+        COPROCESSOR_SEGMENT_OVERRUN => "COPROCESSOR SEGMENT OVERRUN",
+        INVALID_TSS => "INVALID TSS",
+        SEGMENT_NOT_PRESENT => "SEGMENT NOT PRESENT",
+        STACK_SEGMENT_FAULT => "STACK SEGMENT FAULT",
+        GENERAL_PROTECTION_FAULT => "GENERAL PROTECTION FAULT",
+        PAGE_FAULT => "PAGE FAULT",
+        X87_FLOATING_POINT => "X87 FLOATING POINT",
+        ALIGNMENT_CHECK => "ALIGNMENT CHECK",
+        MACHINE_CHECK => "MACHINE CHECK",
+        SIMD_FLOATING_POINT => "SIMD FLOATING POINT",
+        VIRTUALIZATION => "VIRTUALIZATION",
+        CONTROL_PROTECTION => "CONTROL PROTECTION",
+        HYPERVISOR_INJECTION => "HYPERVISOR INJECTION",
+        VMM_COMMUNICATION => "VMM COMMUNICATION",
+        SECURITY_EXCEPTION => "SECURITY EXCEPTION",
+        RESERVED_15 | RESERVED_22...RESERVED_27 | RESERVED_31 => "RESERVED",
+        _ => "UNKNOWN",
+    }
+}
+
+extern "C" fn exception_handler(context: &mut InterruptContext, vector: u64) {
+    match vector {
+        // execution continues after these
+        BREAKPOINT | DEBUG => {
+            report!("\nEXCEPTION: {}\n{}", name(vector), context);
+            return;
+        }
+        PAGE_FAULT => report_page_fault(context),
+        INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+            report!("\nEXCEPTION: {}", name(vector));
+            report_selector_error(context.error_code);
+        }
+        SIMD_FLOATING_POINT => {
+            report!("\nEXCEPTION: {}", name(vector));
+            if let Some(mxcsr) = read_mxcsr() {
+                report!("MXCSR: {:#010x}", mxcsr);
+            }
+        }
+        _ => {
+            report!("\nEXCEPTION: {}", name(vector));
+            if context.error_code != 0 {
+                report!("error code: {:#x}", context.error_code);
+            }
+        }
+    }
+
+    report!("{}", context);
+    report!("CR0: {:#018x} CR2: {:#018x} CR3: {:#018x} CR4: {:#018x}",
+            cpu::read_cr0(), cpu::read_cr2(), cpu::read_cr3(), cpu::read_cr4_raw());
+    super::nmi::disable_watchdog();
+    loop {}
+}
+
+/// Decode the error code of exceptions caused by loading a segment selector
+fn report_selector_error(error_code: u64) {
+    if error_code == 0 {
+        report!("error code: 0, not caused by a selector");
+        return;
+    }
+
+    let table = if error_code & 0b10 != 0 {
+        "IDT"
+    } else if error_code & 0b100 != 0 {
+        "LDT"
+    } else {
+        "GDT"
+    };
+    report!("error code: {:#x}, selector index: {} in {}{}", error_code, (error_code >> 3) & 0x1fff,
+            table, if error_code & 1 != 0 { ", external event" } else { "" });
+}
+
+fn report_page_fault(context: &InterruptContext) {
+    let address = cpu::read_cr2();
+    if let Some(stack) = memory::stack_for_guard_page(address) {
+        report!("\nEXCEPTION: STACK OVERFLOW\nstack of {} ({:#x}-{:#x}) overflowed at {:#x}, RIP: {:#x}",
+                stack.owner, stack.bottom, stack.top, address, context.rip);
+        return;
+    }
+
+    let error_code = context.error_code;
+    let access = if error_code & (1 << 4) != 0 {
+        "instruction fetch"
+    } else if error_code & (1 << 1) != 0 {
+        "write"
+    } else {
+        "read"
+    };
+    report!("\nEXCEPTION: PAGE FAULT at {:#x}\nerror code: {:#x}, {} of a {} in {} mode{}{}",
+            address, error_code, access,
+            if error_code & 1 != 0 { "protected page" } else { "non-present page" },
+            if error_code & (1 << 2) != 0 { "user" } else { "kernel" },
+            if error_code & (1 << 3) != 0 { ", reserved bit set" } else { "" },
+            if error_code & (1 << 5) != 0 { ", protection key violation" } else { "" });
+}
+
+/// None if SSE is not enabled, `stmxcsr` would raise an invalid opcode exception
+fn read_mxcsr() -> Option<u32> {
+    if !cpu::read_cr4().contains(cpu::Cr4Flags::OSFXSR) {
+        return None;
+    }
+    let mut value: u32 = 0;
+    unsafe { asm!("stmxcsr $0" : "=*m"(&mut value) ::: "volatile"); }
+    Some(value)
+}
@@ -0,0 +1,451 @@
+//! Dispatch of hardware interrupts to handlers registered at runtime.
+//! Every vector from 32 to 255 gets a small stub that pushes its vector
+//! number and jumps to common code saving the registers, so the vector
+//! is known when the handlers are looked up. Several handlers can share
+//! a line, the line is unmasked while at least one is registered.
+//! The ISA IRQs are delivered through the I/O APIC if there is one, and
+//! through the 8259 PICs otherwise. Both use the same vectors.
+//! The vectors above the legacy IRQs are handed out to drivers, which have
+//! their devices send them as message signaled interrupts.
+//! Tasklets scheduled by the handlers run after the end of interrupt.
+//! Every vector counts its interrupts per CPU and the cycles its handlers take.
+
+use core::cmp;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::structures::idt::{InterruptDescriptorTable, HandlerFunc};
+
+use sync::IrqMutex;
+
+use drivers::pic::PICS;
+use drivers::apic;
+use cpu;
+
+use super::InterruptContext;
+
+/// First vector not used by CPU exceptions, the legacy IRQs are mapped from here
+pub const IRQ_BASE: u8 = 32;
+const VECTOR_COUNT: usize = 256 - IRQ_BASE as usize;
+/// Lines of the two 8259 PICs
+const LEGACY_IRQ_COUNT: u8 = 16;
+/// Vectors that can be allocated, the ones from `DYNAMIC_VECTOR_END` on are
+/// kept for inter-processor interrupts and the spurious vector
+const DYNAMIC_VECTOR_START: u8 = IRQ_BASE + LEGACY_IRQ_COUNT;
+const DYNAMIC_VECTOR_END: u8 = 0xf0;
+
+const MAX_HANDLERS_PER_LINE: usize = 4;
+/// CPUs with separate interrupt counts, higher APIC IDs share the last one
+const MAX_CPUS: usize = 8;
+/// Size of each stub in `irq_stubs`
+const STUB_SIZE: usize = 16;
+
+/// Called for an interrupt on a line it was registered for.
+/// Returns whether its device raised the interrupt.
+pub type IrqHandler = fn() -> bool;
+
+#[derive(Clone, Copy)]
+struct Handler {
+    name: &'static str,
+    function: IrqHandler,
+}
+
+type HandlerList = [Option<Handler>; MAX_HANDLERS_PER_LINE];
+
+#[derive(Clone, Copy)]
+struct VectorStats {
+    counts: [u64; MAX_CPUS],
+    /// Interrupts none of the handlers claimed
+    unhandled: u64,
+    /// Time stamp counter cycles spent in the handlers
+    total_cycles: u64,
+    min_cycles: u64,
+    max_cycles: u64,
+}
+
+impl VectorStats {
+    fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+const NO_STATS: VectorStats = VectorStats {
+    counts: [0; MAX_CPUS],
+    unhandled: 0,
+    total_cycles: 0,
+    min_cycles: u64::max_value(),
+    max_cycles: 0,
+};
+
+static SPURIOUS_IRQS: AtomicUsize = AtomicUsize::new(0);
+
+static HANDLERS: IrqMutex<[HandlerList; VECTOR_COUNT]> = IrqMutex::new([[None; MAX_HANDLERS_PER_LINE]; VECTOR_COUNT]);
+static STATS: IrqMutex<[VectorStats; VECTOR_COUNT]> = IrqMutex::new([NO_STATS; VECTOR_COUNT]);
+/// One bit per vector, set while it is allocated
+static ALLOCATED_VECTORS: IrqMutex<[u64; 4]> = IrqMutex::new([0; 4]);
+
+// The stubs push the vector number where exceptions have their error code, so it
+// ends up in `InterruptContext::error_code`. `irq_common` also passes it as second
+// argument of `irq_dispatch`, like the exception stubs pass their vector.
+global_asm!(r#"
+.section .text
+.global irq_stubs
+.align 16
+irq_stubs:
+.set irq_vector, 32
+.rept 224
+    .align 16
+    pushq $irq_vector
+    jmp irq_common
+    .set irq_vector, irq_vector + 1
+.endr
+
+irq_common:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    mov %rsp, %rdi
+    mov 120(%rsp), %rsi
+    sub $8, %rsp
+    call irq_dispatch
+    add $8, %rsp
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+    add $8, %rsp
+    iretq
+"#);
+
+extern {
+    fn irq_stubs();
+}
+
+/// Point the entries of all vectors from `IRQ_BASE` to their stubs
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    for vector in IRQ_BASE as usize..256 {
+        let stub = irq_stubs as usize + (vector - IRQ_BASE as usize) * STUB_SIZE;
+        unsafe { idt[vector].set_handler_fn(mem::transmute::<usize, HandlerFunc>(stub)); }
+    }
+}
+
+/// Switch to the APICs if they are present, and mask all legacy lines,
+/// they are unmasked once a handler is registered
+pub fn init() {
+    // the PICs stay masked for good when the APICs take over
+    for irq in 0..LEGACY_IRQ_COUNT {
+        PICS.mask(irq);
+    }
+    if apic::init(IRQ_BASE) {
+        println!("interrupts routed through the APIC{}",
+                 if apic::local_apic().is_x2apic() { " in x2APIC mode" } else { "" });
+    }
+}
+
+/// Vector `irq` is delivered on
+fn irq_vector(irq: u8) -> u8 {
+    if apic::enabled() {
+        IRQ_BASE + irq
+    } else {
+        PICS.vector(irq)
+    }
+}
+
+/// Legacy IRQ delivered on `vector`, if any
+fn vector_irq(vector: u8) -> Option<u8> {
+    if apic::enabled() {
+        if vector >= IRQ_BASE && vector < IRQ_BASE + LEGACY_IRQ_COUNT {
+            Some(vector - IRQ_BASE)
+        } else {
+            None
+        }
+    } else {
+        PICS.irq(vector)
+    }
+}
+
+fn mask(irq: u8) {
+    if apic::enabled() {
+        apic::mask_isa_irq(irq);
+    } else {
+        PICS.mask(irq);
+    }
+}
+
+fn unmask(irq: u8) {
+    if apic::enabled() {
+        apic::unmask_isa_irq(irq);
+    } else {
+        PICS.unmask(irq);
+    }
+}
+
+/// Add `handler` to the handlers of `vector`.
+/// Returns whether it is the first one, or None if there is no free slot left.
+fn add_handler(vector: u8, name: &'static str, handler: IrqHandler) -> Option<bool> {
+    let mut handlers = HANDLERS.lock();
+    let line = &mut handlers[(vector - IRQ_BASE) as usize];
+    let first = line.iter().all(|slot| slot.is_none());
+    let added = match line.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(Handler { name: name, function: handler });
+            true
+        }
+        None => false,
+    };
+    if added { Some(first) } else { None }
+}
+
+/// Remove `handler` from the handlers of `vector`.
+/// Returns whether it was the last one, or None if it was not registered.
+fn remove_handler(vector: u8, handler: IrqHandler) -> Option<bool> {
+    let mut handlers = HANDLERS.lock();
+    let line = &mut handlers[(vector - IRQ_BASE) as usize];
+    let found = match line.iter_mut().find(|slot| {
+        slot.map_or(false, |registered| registered.function as usize == handler as usize)
+    }) {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    };
+    let last = line.iter().all(|slot| slot.is_none());
+    if found { Some(last) } else { None }
+}
+
+/// Add `handler` to the handlers of `irq`, unmasking the line if it is the first one.
+/// Returns false if the line has no free handler slot left.
+pub fn register_irq(irq: u8, name: &'static str, handler: IrqHandler) -> bool {
+    assert!(irq < LEGACY_IRQ_COUNT, "register_irq: invalid irq {}", irq);
+
+    match add_handler(irq_vector(irq), name, handler) {
+        Some(first) => {
+            if first {
+                unmask(irq);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Remove `handler` from the handlers of `irq`, masking the line if it was the last one.
+/// Returns false if the handler was not registered.
+pub fn unregister_irq(irq: u8, handler: IrqHandler) -> bool {
+    assert!(irq < LEGACY_IRQ_COUNT, "unregister_irq: invalid irq {}", irq);
+
+    match remove_handler(irq_vector(irq), handler) {
+        Some(last) => {
+            if last {
+                mask(irq);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+fn is_dynamic(vector: u8) -> bool {
+    vector >= DYNAMIC_VECTOR_START && vector < DYNAMIC_VECTOR_END
+}
+
+/// Allocate `count` consecutive vectors, the first one a multiple of `align`,
+/// which has to be a power of two. Returns the first vector.
+/// They have to be delivered through the local APIC, there is no line to mask.
+pub fn allocate_vectors(count: usize, align: usize) -> Option<u8> {
+    assert!(count > 0 && align.is_power_of_two(), "allocate_vectors: invalid count {} or align {}",
+            count, align);
+
+    let mut allocated = ALLOCATED_VECTORS.lock();
+    let first = {
+        let is_free = |vector: usize| allocated[vector / 64] & (1 << (vector % 64)) == 0;
+        (DYNAMIC_VECTOR_START as usize..DYNAMIC_VECTOR_END as usize).find(|&first| {
+            first % align == 0 && first + count <= DYNAMIC_VECTOR_END as usize &&
+                (first..first + count).all(&is_free)
+        })
+    };
+
+    if let Some(first) = first {
+        for vector in first..first + count {
+            allocated[vector / 64] |= 1 << (vector % 64);
+        }
+    }
+    first.map(|first| first as u8)
+}
+
+/// Free vectors returned by `allocate_vectors`, together with their handlers
+pub fn free_vectors(first: u8, count: usize) {
+    let mut handlers = HANDLERS.lock();
+    let mut allocated = ALLOCATED_VECTORS.lock();
+    for vector in first as usize..first as usize + count {
+        assert!(is_dynamic(vector as u8) && allocated[vector / 64] & (1 << (vector % 64)) != 0,
+                "free_vectors: vector {} is not allocated", vector);
+        allocated[vector / 64] &= !(1 << (vector % 64));
+        handlers[vector - IRQ_BASE as usize] = [None; MAX_HANDLERS_PER_LINE];
+    }
+}
+
+/// Add `handler` to the handlers of an allocated vector.
+/// Returns false if the vector has no free handler slot left.
+pub fn register_vector(vector: u8, name: &'static str, handler: IrqHandler) -> bool {
+    assert!(is_dynamic(vector), "register_vector: invalid vector {}", vector);
+    add_handler(vector, name, handler).is_some()
+}
+
+/// Remove `handler` from the handlers of an allocated vector.
+/// Returns false if the handler was not registered.
+pub fn unregister_vector(vector: u8, handler: IrqHandler) -> bool {
+    assert!(is_dynamic(vector), "unregister_vector: invalid vector {}", vector);
+    remove_handler(vector, handler).is_some()
+}
+
+/// Copy of the handlers of `vector`. The dumps copy one line at a time
+/// and print without the lock, which keeps interrupts disabled, and the
+/// whole table would not fit on the stack.
+fn handlers(vector: u8) -> HandlerList {
+    HANDLERS.lock()[(vector - IRQ_BASE) as usize]
+}
+
+/// Print the handlers registered for every line
+pub fn print_handlers() {
+    println!("irq handlers:");
+    for irq in 0..LEGACY_IRQ_COUNT {
+        let line = handlers(irq_vector(irq));
+        for handler in line.iter().filter_map(|slot| *slot) {
+            println!("  {}: {}", irq, handler.name);
+        }
+    }
+    for vector in DYNAMIC_VECTOR_START..DYNAMIC_VECTOR_END {
+        let line = handlers(vector);
+        for handler in line.iter().filter_map(|slot| *slot) {
+            println!("  vector {}: {}", vector, handler.name);
+        }
+    }
+}
+
+/// Column of the current CPU in the statistics
+fn current_cpu() -> usize {
+    if apic::enabled() {
+        cmp::min(apic::local_apic().id() as usize, MAX_CPUS - 1)
+    } else {
+        0
+    }
+}
+
+/// Print the interrupts of every vector that got any, per CPU, and the
+/// minimum, average and maximum cycles its handlers took
+pub fn print_stats() {
+    let cpus = {
+        let stats = STATS.lock();
+        let last_cpu = (0..MAX_CPUS).rev().find(|&cpu| stats.iter().any(|vector| vector.counts[cpu] != 0));
+        last_cpu.map_or(1, |cpu| cpu + 1)
+    };
+    print!("       ");
+    for cpu in 0..cpus {
+        print!("       CPU{}", cpu);
+    }
+    println!(" {:>10} {:>10} {:>10} {:>10}  handlers", "unhandled", "min cyc", "avg cyc", "max cyc");
+
+    for index in 0..VECTOR_COUNT {
+        // copied like the handlers, so nothing is printed with the lock held
+        let vector_stats = STATS.lock()[index];
+        let count = vector_stats.count();
+        if count == 0 {
+            continue;
+        }
+        let vector = IRQ_BASE + index as u8;
+        match vector_irq(vector) {
+            Some(irq) => print!("IRQ{:>3}:", irq),
+            None => print!("{:>6}:", vector),
+        }
+        for cpu in 0..cpus {
+            print!(" {:>10}", vector_stats.counts[cpu]);
+        }
+        print!(" {:>10} {:>10} {:>10} {:>10} ", vector_stats.unhandled, vector_stats.min_cycles,
+               vector_stats.total_cycles / count, vector_stats.max_cycles);
+        for handler in handlers(vector).iter().filter_map(|slot| *slot) {
+            print!(" {}", handler.name);
+        }
+        println!("");
+    }
+    println!("   SPU: {:>10}", spurious_irqs());
+    println!("   NMI: {:>10}", super::nmi::nmis());
+    println!("   CMC: {:>10}", super::machine_check::corrected_errors());
+}
+
+/// Spurious interrupts seen so far, from the PICs or the local APIC
+pub fn spurious_irqs() -> usize {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+#[no_mangle]
+pub extern "C" fn irq_dispatch(_context: &mut InterruptContext, vector: u64) {
+    let index = vector as usize - IRQ_BASE as usize;
+    let irq = vector_irq(vector as u8);
+    let spurious = if apic::enabled() {
+        vector == apic::SPURIOUS_VECTOR as u64
+    } else {
+        irq.map_or(false, |irq| PICS.is_spurious(irq))
+    };
+    if spurious {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    // copied, so handlers can unregister themselves
+    let line = HANDLERS.lock()[index];
+
+    let start = cpu::rdtsc();
+    let mut handled = false;
+    for handler in line.iter().filter_map(|slot| *slot) {
+        handled |= (handler.function)();
+    }
+    let cycles = cpu::rdtsc().wrapping_sub(start);
+
+    {
+        let mut stats = STATS.lock();
+        let stats = &mut stats[index];
+        stats.counts[current_cpu()] += 1;
+        if !handled {
+            stats.unhandled += 1;
+        }
+        stats.total_cycles += cycles;
+        stats.min_cycles = cmp::min(stats.min_cycles, cycles);
+        stats.max_cycles = cmp::max(stats.max_cycles, cycles);
+    }
+
+    if apic::enabled() {
+        apic::end_of_interrupt();
+    } else if let Some(irq) = irq {
+        PICS.end_of_interrupt(irq);
+    }
+    if !handled {
+        serial_println!("unhandled interrupt on vector {}", vector);
+    }
+
+    super::deferred::run_tasklets();
+}
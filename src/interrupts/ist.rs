@@ -0,0 +1,60 @@
+//! Stacks of the interrupt stack table. Exceptions that can hit while the
+//! current stack is unusable, or at any instruction, are switched to one of
+//! these by the CPU. Slots are numbered from 1 to 7 like in the TSS, each
+//! one gets a stack with a guard page below it from the stack allocator.
+//! All of them are allocated before the TSS is built, it is never changed.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64;
+use x86_64::structures::tss::TaskStateSegment;
+
+use spin::Mutex;
+
+use memory::{MemoryController, Stack};
+
+const IST_SLOTS: usize = 7;
+
+/// Kept for as long as the TSS points to them, a stack is freed when dropped
+static STACKS: Mutex<[Option<Stack>; IST_SLOTS]> = Mutex::new([None, None, None, None, None, None, None]);
+static TSS_BUILT: AtomicBool = AtomicBool::new(false);
+
+fn check_slot(slot: u8) {
+    assert!(slot >= 1 && slot as usize <= IST_SLOTS, "invalid IST slot {}", slot);
+}
+
+/// Allocate a stack of `pages` for IST `slot`.
+/// Panics if the slot is taken or the TSS was already built.
+pub fn allocate(memory_controller: &mut MemoryController, slot: u8, pages: usize, owner: &'static str) {
+    check_slot(slot);
+    assert!(!TSS_BUILT.load(Ordering::SeqCst), "IST slot {} for {} allocated after the TSS was built",
+            slot, owner);
+
+    let mut stacks = STACKS.lock();
+    let entry = &mut stacks[slot as usize - 1];
+    if let Some(ref stack) = *entry {
+        panic!("IST slot {} for {} is already used by {}", slot, owner, stack.owner());
+    }
+    let stack = memory_controller.alloc_stack(pages, owner)
+        .unwrap_or_else(|| panic!("could not allocate IST stack for {}", owner));
+    *entry = Some(stack);
+}
+
+/// Point the interrupt stack table of `tss` to the allocated stacks
+pub fn fill_tss(tss: &mut TaskStateSegment) {
+    TSS_BUILT.store(true, Ordering::SeqCst);
+    let stacks = STACKS.lock();
+    for (index, stack) in stacks.iter().enumerate() {
+        if let Some(ref stack) = *stack {
+            tss.interrupt_stack_table[index] = x86_64::VirtAddr::new(stack.top() as u64);
+        }
+    }
+}
+
+/// Argument of `set_stack_index` for IST `slot`, which counts from 0.
+/// Panics if the slot has no stack, the CPU would switch to address 0.
+pub fn stack_index(slot: u8) -> u16 {
+    check_slot(slot);
+    assert!(STACKS.lock()[slot as usize - 1].is_some(), "IST slot {} has no stack", slot);
+    slot as u16 - 1
+}
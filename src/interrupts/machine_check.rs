@@ -0,0 +1,297 @@
+//! Machine check architecture. Hardware errors are logged in the MCi banks,
+//! uncorrected ones also raise a machine check exception. Corrected errors
+//! only show up in the banks, so they are polled periodically from the work
+//! queue. Errors can be injected with the `mce` command of the QEMU monitor.
+
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+use cpu;
+
+use super::InterruptContext;
+use super::ist;
+use super::MACHINE_CHECK_IST;
+
+const MACHINE_CHECK: u64 = 18;
+
+const MCG_CAP_MSR: u32 = 0x179;
+const MCG_STATUS_MSR: u32 = 0x17a;
+const MCG_CTL_MSR: u32 = 0x17b;
+/// Bank `i` has its control, status, address and misc registers from `0x400 + 4 * i` on
+const MC0_CTL_MSR: u32 = 0x400;
+
+const MCG_CAP_COUNT: u64 = 0xff;
+const MCG_CAP_CTL_PRESENT: u64 = 1 << 8;
+
+/// Execution can be restarted at the RIP that was pushed
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+/// The pushed RIP points to the instruction that caused the error
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+/// A machine check is in progress, a second one shuts the CPU down
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+const STATUS_VALID: u64 = 1 << 63;
+const STATUS_OVERFLOW: u64 = 1 << 62;
+const STATUS_UNCORRECTED: u64 = 1 << 61;
+const STATUS_ENABLED: u64 = 1 << 60;
+const STATUS_MISC_VALID: u64 = 1 << 59;
+const STATUS_ADDRESS_VALID: u64 = 1 << 58;
+/// The processor context may be corrupt
+const STATUS_PROCESSOR_CONTEXT_CORRUPT: u64 = 1 << 57;
+
+interrupt_stub!(machine_check_stub, MACHINE_CHECK, machine_check_handler);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static BANKS: AtomicUsize = AtomicUsize::new(0);
+static CORRECTED_ERRORS: AtomicUsize = AtomicUsize::new(0);
+/// Set while the exception handler reads and clears the banks, polling stays away from them meanwhile
+static HANDLER_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Point the machine check entry of `idt` to its stub, running on its own stack
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.machine_check.set_handler_fn(mem::transmute(machine_check_stub as unsafe extern "C" fn()))
+                         .set_stack_index(ist::stack_index(MACHINE_CHECK_IST));
+    }
+}
+
+fn bank_msr(bank: usize, register: u32) -> u32 {
+    MC0_CTL_MSR + 4 * bank as u32 + register
+}
+
+fn status_msr(bank: usize) -> u32 {
+    bank_msr(bank, 1)
+}
+
+fn address_msr(bank: usize) -> u32 {
+    bank_msr(bank, 2)
+}
+
+fn misc_msr(bank: usize) -> u32 {
+    bank_msr(bank, 3)
+}
+
+/// Enable reporting of all errors in all banks and the machine check
+/// exception. Errors logged before, e.g. by a previous warm reset, are
+/// reported first. Returns the number of banks, 0 if there is no MCA.
+pub fn init() -> usize {
+    if !cpu::has_mce() || !cpu::has_mca() {
+        return 0;
+    }
+
+    let capabilities = cpu::read_msr(MCG_CAP_MSR);
+    let banks = (capabilities & MCG_CAP_COUNT) as usize;
+    BANKS.store(banks, Ordering::SeqCst);
+
+    poll_banks("boot");
+    unsafe {
+        if capabilities & MCG_CAP_CTL_PRESENT != 0 {
+            cpu::write_msr(MCG_CTL_MSR, !0);
+        }
+        for bank in 0..banks {
+            if bank != 0 || !bank_0_control_reserved() {
+                cpu::write_msr(bank_msr(bank, 0), !0);
+            }
+            cpu::write_msr(status_msr(bank), 0);
+        }
+        let mut cr4 = cpu::read_cr4();
+        cr4.insert(cpu::Cr4Flags::MACHINE_CHECK);
+        cpu::write_cr4(cr4);
+    }
+
+    ENABLED.store(true, Ordering::SeqCst);
+    banks
+}
+
+/// On Intel family 6 before model 0x1a the control register of bank 0
+/// aliases a register owned by the firmware and must not be written
+fn bank_0_control_reserved() -> bool {
+    let (family, model) = cpu::family_model();
+    cpu::is_intel() && family == 6 && model < 0x1a
+}
+
+/// Corrected errors found by polling so far
+pub fn corrected_errors() -> usize {
+    CORRECTED_ERRORS.load(Ordering::Relaxed)
+}
+
+/// Log and clear the errors in the banks, called periodically.
+/// The argument is unused, it is the signature of the work queue.
+pub fn poll(_: usize) {
+    if ENABLED.load(Ordering::SeqCst) {
+        poll_banks("poll");
+    }
+}
+
+/// The exception handler can interrupt polling at any point. Polling skips
+/// the banks while it runs, and only clears a status that still holds the
+/// error it reported, so an error logged meanwhile is not lost.
+fn poll_banks(source: &str) {
+    for bank in 0..BANKS.load(Ordering::SeqCst) {
+        if HANDLER_ACTIVE.load(Ordering::SeqCst) {
+            return;
+        }
+        let status = cpu::read_msr(status_msr(bank));
+        if status & STATUS_VALID == 0 {
+            continue;
+        }
+        if status & STATUS_UNCORRECTED == 0 {
+            CORRECTED_ERRORS.fetch_add(1, Ordering::Relaxed);
+        }
+        report_bank(report, source, bank, status);
+        if cpu::read_msr(status_msr(bank)) == status {
+            unsafe { cpu::write_msr(status_msr(bank), 0); }
+        }
+    }
+}
+
+/// Print a line to the screen and the serial port, for polling, which
+/// runs from the work queue and can wait for the locks
+fn report(args: fmt::Arguments) {
+    println!("{}", args);
+    serial_println!("{}", args);
+}
+
+/// Log the error in `bank`, whose status register reads `status`, with
+/// `report`. The exception handler passes `nmi::report_unlocked`.
+fn report_bank(report: fn(fmt::Arguments), source: &str, bank: usize, status: u64) {
+    let code = (status & 0xffff) as u16;
+    report(format_args!("MCE ({}): bank {}: {} {} error{}{}{}",
+                        source, bank,
+                        if status & STATUS_UNCORRECTED != 0 { "uncorrected" } else { "corrected" },
+                        ErrorCode(code),
+                        if status & STATUS_OVERFLOW != 0 { ", earlier errors lost" } else { "" },
+                        if status & STATUS_PROCESSOR_CONTEXT_CORRUPT != 0 { ", processor context corrupt" } else { "" },
+                        if status & STATUS_ENABLED == 0 { ", not signaled" } else { "" }));
+    report(format_args!("  status: {:#018x}, model specific code: {:#06x}, corrected count: {}",
+                        status, (status >> 16) & 0xffff, (status >> 38) & 0x7fff));
+    if status & STATUS_ADDRESS_VALID != 0 {
+        report(format_args!("  address: {:#018x}", cpu::read_msr(address_msr(bank))));
+    }
+    if status & STATUS_MISC_VALID != 0 {
+        report(format_args!("  misc: {:#018x}", cpu::read_msr(misc_msr(bank))));
+    }
+}
+
+/// MCA error code, the low 16 bits of a bank status
+struct ErrorCode(u16);
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const TRANSACTIONS: [&str; 3] = ["instruction", "data", "generic"];
+        const LEVELS: [&str; 4] = ["L0", "L1", "L2", "generic level"];
+        const REQUESTS: [&str; 9] = ["generic", "read", "write", "data read", "data write",
+                                     "instruction fetch", "prefetch", "eviction", "snoop"];
+        const MEMORY_REQUESTS: [&str; 5] = ["generic", "read", "write", "address/command", "scrub"];
+        const BUS_TARGETS: [&str; 4] = ["memory", "reserved", "I/O", "other"];
+
+        // bit 12 only tells whether corrected errors of this kind are filtered
+        let code = self.0 & !(1 << 12);
+        let transaction = TRANSACTIONS.get(((code >> 2) & 0b11) as usize).unwrap_or(&"reserved");
+        let level = LEVELS[(code & 0b11) as usize];
+        let request = REQUESTS.get(((code >> 4) & 0xf) as usize).unwrap_or(&"reserved");
+
+        if code & 0xf800 == 0x0800 {
+            let target = BUS_TARGETS[((code >> 2) & 0b11) as usize];
+            write!(f, "bus/interconnect ({} {} of {})", level, request, target)
+        } else if code & 0xff00 == 0x0100 {
+            write!(f, "cache ({} {} {})", level, request, transaction)
+        } else if code & 0xff80 == 0x0080 {
+            let memory_request = MEMORY_REQUESTS.get(((code >> 4) & 0b111) as usize).unwrap_or(&"reserved");
+            match code & 0xf {
+                0xf => write!(f, "memory controller ({})", memory_request),
+                channel => write!(f, "memory controller ({}, channel {})", memory_request, channel),
+            }
+        } else if code & 0xfff0 == 0x0010 {
+            write!(f, "TLB ({} {})", level, transaction)
+        } else if code & 0xfffc == 0x000c {
+            write!(f, "generic cache hierarchy ({})", level)
+        } else {
+            match code {
+                0x0000 => write!(f, "no"),
+                0x0001 => write!(f, "unclassified"),
+                0x0002 => write!(f, "microcode ROM parity"),
+                0x0003 => write!(f, "external"),
+                0x0004 => write!(f, "FRC"),
+                0x0005 => write!(f, "internal parity"),
+                0x0400 => write!(f, "internal timer"),
+                code if code & 0xfc00 == 0x0400 => write!(f, "internal unclassified"),
+                code => write!(f, "unknown ({:#06x})", code),
+            }
+        }
+    }
+}
+
+extern "C" fn machine_check_handler(context: &mut InterruptContext, _vector: u64) {
+    HANDLER_ACTIVE.store(true, Ordering::SeqCst);
+    let global_status = cpu::read_msr(MCG_STATUS_MSR);
+    nmi_report!("\nEXCEPTION: MACHINE CHECK, RIP {}{}",
+                if global_status & MCG_STATUS_RIPV != 0 { "valid" } else { "invalid" },
+                if global_status & MCG_STATUS_EIPV != 0 { ", caused by the instruction at RIP" } else { "" });
+
+    let mut fatal = global_status & MCG_STATUS_RIPV == 0;
+    for bank in 0..BANKS.load(Ordering::SeqCst) {
+        let status = cpu::read_msr(status_msr(bank));
+        if status & STATUS_VALID == 0 {
+            continue;
+        }
+        report_bank(super::nmi::report_unlocked, "exception", bank, status);
+        if status & STATUS_UNCORRECTED != 0 {
+            fatal = true;
+        }
+        unsafe { cpu::write_msr(status_msr(bank), 0); }
+    }
+
+    if fatal {
+        nmi_report!("{}", context);
+        nmi_report!("CR0: {:#018x} CR2: {:#018x} CR3: {:#018x} CR4: {:#018x}",
+                    cpu::read_cr0(), cpu::read_cr2(), cpu::read_cr3(), cpu::read_cr4_raw());
+        super::nmi::disable_watchdog();
+        loop {}
+    }
+
+    HANDLER_ACTIVE.store(false, Ordering::SeqCst);
+    // another machine check would shut the CPU down while this is set
+    unsafe { cpu::write_msr(MCG_STATUS_MSR, global_status & !MCG_STATUS_MCIP); }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::String;
+
+    fn decode(code: u16) -> String {
+        format!("{}", ErrorCode(code))
+    }
+
+    #[test]
+    fn compound_codes() {
+        assert_eq!(decode(0x0135), "cache (L1 data read data)");
+        // the correction report filtering bit does not change the meaning
+        assert_eq!(decode(0x1135), "cache (L1 data read data)");
+        assert_eq!(decode(0x01f1), "cache (L1 reserved instruction)");
+        assert_eq!(decode(0x010c), "cache (L0 generic reserved)");
+        assert_eq!(decode(0x0016), "TLB (L2 data)");
+        assert_eq!(decode(0x000d), "generic cache hierarchy (L1)");
+        assert_eq!(decode(0x0e0b), "bus/interconnect (generic level generic of I/O)");
+    }
+
+    #[test]
+    fn memory_controller_codes() {
+        assert_eq!(decode(0x009f), "memory controller (read)");
+        assert_eq!(decode(0x00a2), "memory controller (write, channel 2)");
+        assert_eq!(decode(0x00f0), "memory controller (reserved, channel 0)");
+    }
+
+    #[test]
+    fn simple_codes() {
+        assert_eq!(decode(0x0000), "no");
+        assert_eq!(decode(0x0005), "internal parity");
+        assert_eq!(decode(0x0400), "internal timer");
+        assert_eq!(decode(0x0401), "internal unclassified");
+        assert_eq!(decode(0x0006), "unknown (0x0006)");
+    }
+}
@@ -1,8 +1,16 @@
+#[macro_use]
+mod stubs;
 mod gdt;
+mod exceptions;
+#[macro_use]
+mod nmi;
+mod machine_check;
+mod ist;
+pub mod irq;
+pub mod deferred;
 
 use x86_64;
 
-use x86_64::structures::idt::ExceptionStackFrame;
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::SegmentSelector;
@@ -12,10 +20,34 @@ use x86_64::instructions::tables::load_tss;
 
 use spin::Once;
 
+use core::char;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use memory::MemoryController;
 use drivers;
+use cpu;
+
+pub use self::stubs::InterruptContext;
+pub use self::nmi::disable_watchdog;
+
+const TIMER_IRQ: u8 = 0;
+const KEYBOARD_IRQ: u8 = 1;
+
+/// Timer ticks between two polls of the machine check banks for corrected errors
+const MACHINE_CHECK_POLL_INTERVAL: usize = 1000;
+
+// IST slots, counted from 1 like in the TSS
+const DOUBLE_FAULT_IST: u8 = 1;
+/// Page faults get their own stack, a fault caused by a stack overflow
+/// could not push the exception frame on the overflowed stack
+const PAGE_FAULT_IST: u8 = 2;
+/// NMIs can interrupt any code, even at the start of another handler
+const NMI_IST: u8 = 3;
+const MACHINE_CHECK_IST: u8 = 4;
+/// A debug exception can be raised by a breakpoint on the stack itself
+const DEBUG_IST: u8 = 5;
 
-const DOUBLE_FAULT_IST_INDEX: usize = 0;
+static TICKS: AtomicUsize = AtomicUsize::new(0);
 
 static TSS: Once<TaskStateSegment> = Once::new();
 static GDT: Once<gdt::Gdt> = Once::new();
@@ -23,25 +55,24 @@ static GDT: Once<gdt::Gdt> = Once::new();
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
-                            .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
-        }
-        idt[32].set_handler_fn(timer_handler);
-        idt[33].set_handler_fn(keyboard_handler);
+        exceptions::install(&mut idt);
+        nmi::install(&mut idt);
+        machine_check::install(&mut idt);
+        irq::install(&mut idt);
         idt
     };
 }
 
 pub fn init(memory_controller: &mut MemoryController) {
-    let double_fault_stack = memory_controller.alloc_stack(1)
-                                              .expect("could not allocate double fault stack");
-
     let tss = TSS.call_once(|| {
+        ist::allocate(memory_controller, DOUBLE_FAULT_IST, 2, "double fault");
+        ist::allocate(memory_controller, PAGE_FAULT_IST, 2, "page fault");
+        ist::allocate(memory_controller, NMI_IST, 2, "nmi");
+        ist::allocate(memory_controller, MACHINE_CHECK_IST, 2, "machine check");
+        ist::allocate(memory_controller, DEBUG_IST, 1, "debug");
+
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = x86_64::VirtAddr::new(
-            double_fault_stack.top() as u64);
+        ist::fill_tss(&mut tss);
         tss
     });
 
@@ -64,29 +95,61 @@ pub fn init(memory_controller: &mut MemoryController) {
         load_tss(tss_selector);
     }
     IDT.load();
+
+    let banks = machine_check::init();
+    if banks > 0 {
+        println!("machine check architecture enabled with {} banks", banks);
+    }
+
+    irq::init();
+    irq::register_irq(TIMER_IRQ, "timer", timer_handler);
+    irq::register_irq(KEYBOARD_IRQ, "keyboard", keyboard_handler);
+    if nmi::enable_watchdog() {
+        println!("NMI watchdog enabled");
+    }
+
     x86_64::instructions::interrupts::enable();
 }
 
-extern "x86-interrupt" 
-fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+/// Run `f` with interrupts disabled, restoring the previous state afterwards
+pub fn without_interrupts<F, R>(f: F) -> R
+    where F: FnOnce() -> R
+{
+    let enabled = cpu::disable_interrupts();
+    let result = f();
+    cpu::restore_interrupts(enabled);
+    result
 }
 
-extern "x86-interrupt" 
-fn double_fault_handler(stack_frame: &mut ExceptionStackFrame, _error_code: u64) {
-    println!("\nEXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-    loop {}
+/// Number of timer interrupts since interrupts were enabled
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
 }
 
-extern "x86-interrupt" 
-fn timer_handler(_stack_frame: &mut ExceptionStackFrame) {
-    drivers::pic::MASTER.send_eoi();
+fn timer_handler() -> bool {
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if ticks % MACHINE_CHECK_POLL_INTERVAL == 0 {
+        deferred::queue_work(machine_check::poll, 0);
+    }
+    true
 }
 
-extern "x86-interrupt" 
-fn keyboard_handler(_stack_frame: &mut ExceptionStackFrame) {
+fn keyboard_handler() -> bool {
     if let Some(c) = drivers::keyboard::read_char() {
-        print!("{}", c);
+        // printing is slow and keeps interrupts disabled while it holds the VGA lock
+        deferred::queue_work(echo_char, c as u32 as usize);
+    }
+    true
+}
+
+/// Echo a typed character, escape dumps the interrupt handlers and statistics
+fn echo_char(c: usize) {
+    match char::from_u32(c as u32) {
+        Some('\x1b') => {
+            irq::print_handlers();
+            irq::print_stats();
+        }
+        Some(c) => print!("{}", c),
+        None => {}
     }
-    drivers::pic::MASTER.send_eoi();
 }
\ No newline at end of file
@@ -0,0 +1,238 @@
+//! Non-maskable interrupts. They run on their own stack and can arrive while
+//! any lock is held, even an `IrqMutex`, so the handler takes no locks and
+//! only prints if the console and serial locks are free.
+//! Besides the hardware errors reported through the system control ports,
+//! NMIs come from the watchdog: performance counter 0 counts unhalted cycles
+//! and raises an NMI on overflow. If the timer ticks did not advance over
+//! several of them, the CPU is stuck with interrupts disabled.
+
+use core::fmt::{self, Write};
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+use cpu;
+use drivers::apic;
+use drivers::io::{inb, outb};
+use drivers::serial::SERIAL1;
+use drivers::video::console::WRITER;
+
+use super::InterruptContext;
+use super::ist;
+use super::NMI_IST;
+
+const NON_MASKABLE_INTERRUPT: u64 = 2;
+
+const RFLAGS_INTERRUPT_FLAG: u64 = 1 << 9;
+
+/// System control port B, reports hardware errors signaled by NMI
+const SYSTEM_CONTROL_B: u16 = 0x61;
+const SYSTEM_CONTROL_B_SERR: u8 = 1 << 7;
+const SYSTEM_CONTROL_B_IOCHK: u8 = 1 << 6;
+/// Writing one disables and clears the SERR#/IOCHK# NMI source
+const SYSTEM_CONTROL_B_SERR_CLEAR: u8 = 1 << 2;
+const SYSTEM_CONTROL_B_IOCHK_CLEAR: u8 = 1 << 3;
+/// Only the low bits of port B can be written
+const SYSTEM_CONTROL_B_WRITABLE: u8 = 0x0f;
+/// System control port A, chipsets with a watchdog timer report its timeout here
+const SYSTEM_CONTROL_A: u16 = 0x92;
+const SYSTEM_CONTROL_A_WATCHDOG: u8 = 1 << 4;
+
+/// CPUID leaf describing the architectural performance monitoring
+const PERFORMANCE_MONITORING_LEAF: u32 = 0x0a;
+const PMC0_MSR: u32 = 0xc1;
+const PERFEVTSEL0_MSR: u32 = 0x186;
+/// Enable bits of the counters, version 2 and later. They are set at reset,
+/// but firmware may have cleared them.
+const PERF_GLOBAL_CTRL_MSR: u32 = 0x38f;
+/// Writing a one clears the overflow status of a counter, version 2 and later
+const PERF_GLOBAL_OVF_CTRL_MSR: u32 = 0x390;
+const PERF_GLOBAL_PMC0: u64 = 1 << 0;
+/// Unhalted core cycles, architectural event 0
+const EVENT_UNHALTED_CORE_CYCLES: u64 = 0x3c;
+const PERFEVTSEL_OS: u64 = 1 << 17;
+const PERFEVTSEL_INT: u64 = 1 << 20;
+const PERFEVTSEL_ENABLE: u64 = 1 << 22;
+
+/// Cycles between two watchdog NMIs. Writes to the counter are sign extended
+/// from bit 31, so it has to be below 2^31.
+const WATCHDOG_PERIOD: u64 = 1 << 30;
+/// Watchdog NMIs without a timer tick before the CPU is reported as stuck
+const WATCHDOG_THRESHOLD: usize = 5;
+
+interrupt_stub!(nmi_stub, NON_MASKABLE_INTERRUPT, nmi_handler);
+
+static NMIS: AtomicUsize = AtomicUsize::new(0);
+
+static WATCHDOG_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Version of the architectural performance monitoring
+static PERFORMANCE_MONITORING_VERSION: AtomicUsize = AtomicUsize::new(0);
+/// Width of the performance counters in bits
+static COUNTER_WIDTH: AtomicUsize = AtomicUsize::new(0);
+static LAST_TICKS: AtomicUsize = AtomicUsize::new(0);
+static STALLED_PERIODS: AtomicUsize = AtomicUsize::new(0);
+
+/// Point the NMI entry of `idt` to its stub, running on its own stack
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.non_maskable_interrupt.set_handler_fn(mem::transmute(nmi_stub as unsafe extern "C" fn()))
+                                  .set_stack_index(ist::stack_index(NMI_IST));
+    }
+}
+
+/// Print to the screen and the serial port, skipping them if their lock is
+/// held, the NMI or machine check may have interrupted its owner
+macro_rules! nmi_report {
+    ($($arg:tt)*) => ({
+        $crate::interrupts::nmi::report_unlocked(format_args!($($arg)*));
+    });
+}
+
+pub fn report_unlocked(args: fmt::Arguments) {
+    if let Some(mut writer) = WRITER.try_lock() {
+        let _ = writer.write_fmt(format_args!("{}\n", args));
+    }
+    if let Some(mut serial) = SERIAL1.try_lock() {
+        let _ = serial.write_fmt(format_args!("{}\n", args));
+    }
+}
+
+/// NMIs handled so far
+pub fn nmis() -> usize {
+    NMIS.load(Ordering::Relaxed)
+}
+
+extern "C" fn nmi_handler(context: &mut InterruptContext, _vector: u64) {
+    NMIS.fetch_add(1, Ordering::Relaxed);
+
+    // an NMI can have several sources at once, all of them are checked
+    let mut handled = watchdog_nmi(context);
+    handled |= system_control_nmi();
+
+    if !handled {
+        nmi_report!("\nNMI for unknown reason\n{}", context);
+    }
+}
+
+/// Decode and clear the errors latched in the system control ports
+fn system_control_nmi() -> bool {
+    let port_b = unsafe { inb(SYSTEM_CONTROL_B) };
+    let port_a = unsafe { inb(SYSTEM_CONTROL_A) };
+    let mut handled = false;
+
+    if port_b & SYSTEM_CONTROL_B_SERR != 0 {
+        nmi_report!("\nNMI: system error (SERR#), memory parity or PCI system error");
+        clear_system_control_b(SYSTEM_CONTROL_B_SERR_CLEAR);
+        handled = true;
+    }
+    if port_b & SYSTEM_CONTROL_B_IOCHK != 0 {
+        nmi_report!("\nNMI: I/O channel check (IOCHK#)");
+        clear_system_control_b(SYSTEM_CONTROL_B_IOCHK_CLEAR);
+        handled = true;
+    }
+    if port_a & SYSTEM_CONTROL_A_WATCHDOG != 0 {
+        nmi_report!("\nNMI: chipset watchdog timer expired");
+        handled = true;
+    }
+    handled
+}
+
+/// Toggle the disable bit of a source, which clears its latched error
+fn clear_system_control_b(clear: u8) {
+    unsafe {
+        let value = inb(SYSTEM_CONTROL_B) & SYSTEM_CONTROL_B_WRITABLE;
+        outb(SYSTEM_CONTROL_B, value | clear);
+        outb(SYSTEM_CONTROL_B, value & !clear);
+    }
+}
+
+/// Start performance counter 0 so that it overflows after `WATCHDOG_PERIOD` cycles
+fn arm_counter() {
+    unsafe { cpu::write_msr(PMC0_MSR, (WATCHDOG_PERIOD as i64).wrapping_neg() as u64); }
+}
+
+/// Start the watchdog. It needs the local APIC and the architectural
+/// performance counters, which are missing in QEMU without KVM.
+/// Returns whether it is running.
+pub fn enable_watchdog() -> bool {
+    if !apic::enabled() || cpu::max_cpuid_leaf() < PERFORMANCE_MONITORING_LEAF {
+        return false;
+    }
+    let monitoring = cpu::cpuid(PERFORMANCE_MONITORING_LEAF, 0);
+    let version = monitoring.eax & 0xff;
+    let counters = (monitoring.eax >> 8) & 0xff;
+    let width = (monitoring.eax >> 16) & 0xff;
+    // set bits in EBX mark unavailable events, bit 0 is unhalted core cycles
+    if version == 0 || counters == 0 || monitoring.ebx & 1 != 0 {
+        return false;
+    }
+
+    PERFORMANCE_MONITORING_VERSION.store(version as usize, Ordering::Relaxed);
+    COUNTER_WIDTH.store(width as usize, Ordering::Relaxed);
+    LAST_TICKS.store(super::ticks(), Ordering::Relaxed);
+    STALLED_PERIODS.store(0, Ordering::Relaxed);
+    WATCHDOG_ENABLED.store(true, Ordering::SeqCst);
+
+    unsafe { cpu::write_msr(PERFEVTSEL0_MSR, 0); }
+    arm_counter();
+    apic::local_apic().set_performance_counter_nmi();
+    unsafe {
+        cpu::write_msr(PERFEVTSEL0_MSR, EVENT_UNHALTED_CORE_CYCLES | PERFEVTSEL_OS |
+                       PERFEVTSEL_INT | PERFEVTSEL_ENABLE);
+        if version >= 2 {
+            let enabled = cpu::read_msr(PERF_GLOBAL_CTRL_MSR);
+            cpu::write_msr(PERF_GLOBAL_CTRL_MSR, enabled | PERF_GLOBAL_PMC0);
+        }
+    }
+    true
+}
+
+/// Stop the watchdog, called before the CPU is halted on purpose with
+/// interrupts disabled, which would otherwise be reported as a stall
+pub fn disable_watchdog() {
+    if WATCHDOG_ENABLED.swap(false, Ordering::SeqCst) {
+        unsafe {
+            cpu::write_msr(PERFEVTSEL0_MSR, 0);
+            if PERFORMANCE_MONITORING_VERSION.load(Ordering::Relaxed) >= 2 {
+                let enabled = cpu::read_msr(PERF_GLOBAL_CTRL_MSR);
+                cpu::write_msr(PERF_GLOBAL_CTRL_MSR, enabled & !PERF_GLOBAL_PMC0);
+            }
+        }
+        apic::local_apic().mask_performance_counter();
+    }
+}
+
+/// Check whether the counter overflowed, and whether the timer made progress
+/// since the last overflow
+fn watchdog_nmi(context: &InterruptContext) -> bool {
+    if !WATCHDOG_ENABLED.load(Ordering::SeqCst) {
+        return false;
+    }
+    // the counter started negative, its top bit is cleared by the overflow
+    let top_bit = 1 << (COUNTER_WIDTH.load(Ordering::Relaxed) - 1);
+    if cpu::read_msr(PMC0_MSR) & top_bit != 0 {
+        return false;
+    }
+    arm_counter();
+    if PERFORMANCE_MONITORING_VERSION.load(Ordering::Relaxed) >= 2 {
+        // the overflow stays latched in the global status otherwise
+        unsafe { cpu::write_msr(PERF_GLOBAL_OVF_CTRL_MSR, PERF_GLOBAL_PMC0); }
+    }
+    apic::local_apic().set_performance_counter_nmi();
+
+    let ticks = super::ticks();
+    if ticks != LAST_TICKS.swap(ticks, Ordering::Relaxed) {
+        STALLED_PERIODS.store(0, Ordering::Relaxed);
+        return true;
+    }
+    // reported once per stall
+    if STALLED_PERIODS.fetch_add(1, Ordering::Relaxed) + 1 == WATCHDOG_THRESHOLD {
+        nmi_report!("\nNMI WATCHDOG: no timer interrupt for {} periods of {} cycles, interrupts {}\n{}",
+                    WATCHDOG_THRESHOLD, WATCHDOG_PERIOD,
+                    if context.rflags & RFLAGS_INTERRUPT_FLAG != 0 { "enabled" } else { "disabled" }, context);
+        nmi_report!("CR0: {:#018x} CR2: {:#018x} CR3: {:#018x} CR4: {:#018x}",
+                    cpu::read_cr0(), cpu::read_cr2(), cpu::read_cr3(), cpu::read_cr4_raw());
+    }
+    true
+}
@@ -0,0 +1,131 @@
+use core::fmt;
+
+/// State of the interrupted code, saved by the stubs generated by `interrupt_stub!`.
+/// The general purpose registers are followed by the error code (0 if the vector
+/// has none) and the frame pushed by the CPU.
+#[derive(Debug)]
+#[repr(C)]
+pub struct InterruptContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl fmt::Display for InterruptContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RIP: {:#018x} CS: {:#06x} RFLAGS: {:#018x}", self.rip, self.cs, self.rflags)?;
+        writeln!(f, "RSP: {:#018x} SS: {:#06x} RBP: {:#018x}", self.rsp, self.ss, self.rbp)?;
+        writeln!(f, "RAX: {:#018x} RBX: {:#018x} RCX: {:#018x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "RDX: {:#018x} RSI: {:#018x} RDI: {:#018x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "R8:  {:#018x} R9:  {:#018x} R10: {:#018x}", self.r8, self.r9, self.r10)?;
+        writeln!(f, "R11: {:#018x} R12: {:#018x} R13: {:#018x}", self.r11, self.r12, self.r13)?;
+        write!(f, "R14: {:#018x} R15: {:#018x}", self.r14, self.r15)
+    }
+}
+
+macro_rules! save_registers {
+    () => {
+        asm!("push rax
+              push rbx
+              push rcx
+              push rdx
+              push rsi
+              push rdi
+              push rbp
+              push r8
+              push r9
+              push r10
+              push r11
+              push r12
+              push r13
+              push r14
+              push r15"
+              :::: "intel", "volatile");
+    };
+}
+
+macro_rules! restore_registers {
+    () => {
+        asm!("pop r15
+              pop r14
+              pop r13
+              pop r12
+              pop r11
+              pop r10
+              pop r9
+              pop r8
+              pop rbp
+              pop rdi
+              pop rsi
+              pop rdx
+              pop rcx
+              pop rbx
+              pop rax"
+              :::: "intel", "volatile");
+    };
+}
+
+/// Call `$handler` with the saved `InterruptContext` and the vector number.
+/// The CPU pushes 5 words, with the error code and the 15 registers the stack
+/// is misaligned by 8 bytes, which is fixed up around the call.
+macro_rules! call_handler {
+    ($handler:expr, $vector:expr) => {
+        asm!("mov rdi, rsp
+              mov rsi, $0
+              sub rsp, 8
+              call $1
+              add rsp, 8"
+              :: "i"($vector as u64),
+                 "i"($handler as extern "C" fn(&mut $crate::interrupts::InterruptContext, u64))
+              : "rdi", "rsi" : "intel", "volatile");
+    };
+}
+
+/// Define a naked entry stub for `$vector` that saves all general purpose
+/// registers and calls `$handler`. Vectors for which the CPU pushes an error
+/// code have to be marked with `error_code`, the others get a 0 pushed instead.
+macro_rules! interrupt_stub {
+    ($name:ident, $vector:expr, $handler:expr) => {
+        #[naked]
+        unsafe extern "C" fn $name() {
+            asm!("push 0" :::: "intel", "volatile");
+            save_registers!();
+            call_handler!($handler, $vector);
+            restore_registers!();
+            asm!("add rsp, 8
+                  iretq"
+                  :::: "intel", "volatile");
+            ::core::intrinsics::unreachable();
+        }
+    };
+    ($name:ident, $vector:expr, $handler:expr, error_code) => {
+        #[naked]
+        unsafe extern "C" fn $name() {
+            save_registers!();
+            call_handler!($handler, $vector);
+            restore_registers!();
+            asm!("add rsp, 8
+                  iretq"
+                  :::: "intel", "volatile");
+            ::core::intrinsics::unreachable();
+        }
+    };
+}
@@ -7,6 +7,9 @@
 #![feature(panic_handler)]
 #![feature(alloc_error_handler)]
 #![feature(asm)]
+#![feature(global_asm)]
+#![feature(naked_functions)]
+#![feature(core_intrinsics)]
 #![no_std]
 #![cfg_attr(not(test), no_main)]
 #![cfg_attr(test, allow(dead_code, unused_macros, unused_imports, unused_attributes))]
@@ -20,7 +23,6 @@ extern crate multiboot2;
 extern crate x86_64;
 extern crate volatile;
 extern crate bit_field;
-extern crate slab_allocator;
 extern crate uart_16550;
 
 #[macro_use]
@@ -39,12 +41,21 @@ mod drivers;
 /// Memory management
 mod memory;
 
+/// CPU registers and features
+mod cpu;
+
+/// Locks shared with interrupt handlers
+mod sync;
+
 use memory::heap_allocator;
 
 use core::panic::PanicInfo;
 
 mod interrupts;
 
+/// Timer ticks between two working set scans
+const WORKING_SET_SCAN_INTERVAL: usize = 100;
+
 #[cfg(not(test))]
 #[global_allocator]
 static ALLOCATOR: heap_allocator::Allocator = heap_allocator::Allocator;
@@ -96,8 +107,16 @@ pub extern "C" fn _start(multiboot_information_address: usize) -> ! {
     println!("It did not crash!");
     serial_println!("Hello Host{}", "!");
 
+    let mut last_scan = 0;
     loop{
-
+        interrupts::deferred::run_work();
+
+        // sample accessed bits to keep the working set estimates up to date
+        let ticks = interrupts::ticks();
+        if ticks - last_scan >= WORKING_SET_SCAN_INTERVAL {
+            memory_controller.scan_working_sets();
+            last_scan = ticks;
+        }
     }
 }
 
@@ -106,5 +125,6 @@ pub extern "C" fn _start(multiboot_information_address: usize) -> ! {
 #[no_mangle]
 pub extern "C" fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    interrupts::disable_watchdog();
     loop{}
 }
\ No newline at end of file
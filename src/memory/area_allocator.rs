@@ -0,0 +1,190 @@
+use core;
+use core::mem;
+
+use memory::paging::{Page, VirtualAddress, PAGE_SIZE};
+
+const BITS_PER_BLOCK: usize = mem::size_of::<usize>() * 8;
+
+/// Hands out runs of consecutive pages from a fixed virtual range.
+/// Only the addresses are managed, mapping the pages is up to the caller.
+pub struct AreaAllocator<'a> {
+    bitmap: &'a mut [usize],
+    start: Page,
+    size_in_pages: usize,
+}
+
+impl<'a> AreaAllocator<'a> {
+    pub fn new(bitmap: &'a mut [usize], start: Page, size_in_pages: usize) -> AreaAllocator<'a> {
+        assert!(bitmap.len() * BITS_PER_BLOCK >= size_in_pages, "Bitmap used by area allocator is too small");
+        AreaAllocator {
+            bitmap: bitmap,
+            start: start,
+            size_in_pages: size_in_pages,
+        }
+    }
+
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.start.start_address() &&
+            address < self.start.start_address() + self.size_in_pages * PAGE_SIZE
+    }
+
+    /// Reserve `count` consecutive pages, returns the first one
+    pub fn allocate(&mut self, count: usize) -> Option<Page> {
+        if count == 0 || count > self.size_in_pages {
+            return None;
+        }
+
+        let mut run_start = 0;
+        let mut run_length = 0;
+        let mut index = 0;
+        while index < self.size_in_pages {
+            if self.bitmap[index / BITS_PER_BLOCK] == core::usize::MAX {
+                // whole block is used
+                run_length = 0;
+                index = (index / BITS_PER_BLOCK + 1) * BITS_PER_BLOCK;
+                continue;
+            }
+
+            if self.is_used(index) {
+                run_length = 0;
+            } else {
+                if run_length == 0 {
+                    run_start = index;
+                }
+                run_length += 1;
+                if run_length == count {
+                    for i in run_start..run_start + count {
+                        self.set_used(i, true);
+                    }
+                    return Some(self.start + run_start);
+                }
+            }
+            index += 1;
+        }
+        None
+    }
+
+    /// Release `count` pages starting at `start`, previously returned by `allocate`
+    pub fn deallocate(&mut self, start: Page, count: usize) {
+        debug_assert!(self.contains(start.start_address()));
+        let first = (start.start_address() - self.start.start_address()) / PAGE_SIZE;
+        for index in first..first + count {
+            debug_assert!(self.is_used(index), "area was not allocated");
+            self.set_used(index, false);
+        }
+    }
+
+    /// Number of pages not allocated
+    pub fn free_pages(&self) -> usize {
+        (0..self.size_in_pages).filter(|&index| !self.is_used(index)).count()
+    }
+
+    /// Length of the longest run of free pages, the biggest allocation that can still succeed
+    pub fn largest_free_run(&self) -> usize {
+        let mut largest = 0;
+        let mut run_length = 0;
+        for index in 0..self.size_in_pages {
+            if self.is_used(index) {
+                run_length = 0;
+            } else {
+                run_length += 1;
+                if run_length > largest {
+                    largest = run_length;
+                }
+            }
+        }
+        largest
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        (self.bitmap[index / BITS_PER_BLOCK] & (1usize << (index % BITS_PER_BLOCK))) != 0
+    }
+
+    fn set_used(&mut self, index: usize, value: bool) {
+        if value {
+            self.bitmap[index / BITS_PER_BLOCK] |= 1usize << (index % BITS_PER_BLOCK);
+        } else {
+            self.bitmap[index / BITS_PER_BLOCK] &= !(1usize << (index % BITS_PER_BLOCK));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Page number of the start of the area
+    const START: usize = 0x4_0000;
+
+    // built from page numbers, the address check of `containing_address`
+    // depends on the paging mode of the CPU
+    fn page(index: usize) -> Page {
+        Page::from_number(START + index)
+    }
+
+    #[test]
+    fn freed_neighbours_merge() {
+        let mut bitmap = [0usize; 1];
+        let mut area = AreaAllocator::new(&mut bitmap, page(0), 30);
+        assert_eq!(area.allocate(10), Some(page(0)));
+        assert_eq!(area.allocate(10), Some(page(10)));
+        assert_eq!(area.allocate(10), Some(page(20)));
+        assert_eq!(area.allocate(1), None);
+
+        // the runs on both sides of the middle one are not adjacent
+        area.deallocate(page(0), 10);
+        area.deallocate(page(20), 10);
+        assert_eq!(area.free_pages(), 20);
+        assert_eq!(area.largest_free_run(), 10);
+        assert_eq!(area.allocate(11), None);
+
+        area.deallocate(page(10), 10);
+        assert_eq!(area.largest_free_run(), 30);
+        assert_eq!(area.allocate(30), Some(page(0)));
+    }
+
+    #[test]
+    fn run_across_blocks() {
+        let mut bitmap = [0usize; 2];
+        let mut area = AreaAllocator::new(&mut bitmap, page(0), 2 * BITS_PER_BLOCK);
+        assert_eq!(area.allocate(BITS_PER_BLOCK - 4), Some(page(0)));
+        assert_eq!(area.allocate(8), Some(page(BITS_PER_BLOCK - 4)));
+
+        area.deallocate(page(0), BITS_PER_BLOCK - 4);
+        assert_eq!(area.largest_free_run(), BITS_PER_BLOCK - 4);
+        assert_eq!(area.allocate(BITS_PER_BLOCK - 3), None);
+
+        // freeing the run in the middle joins the free pages of both blocks
+        area.deallocate(page(BITS_PER_BLOCK - 4), 8);
+        assert_eq!(area.largest_free_run(), 2 * BITS_PER_BLOCK);
+        assert_eq!(area.allocate(2 * BITS_PER_BLOCK), Some(page(0)));
+    }
+
+    #[test]
+    fn full_block_is_skipped() {
+        let mut bitmap = [0usize; 2];
+        let mut area = AreaAllocator::new(&mut bitmap, page(0), 2 * BITS_PER_BLOCK);
+        assert_eq!(area.allocate(BITS_PER_BLOCK), Some(page(0)));
+        assert_eq!(area.allocate(1), Some(page(BITS_PER_BLOCK)));
+
+        // a run ending at the last page of a full block continues into the next one
+        area.deallocate(page(BITS_PER_BLOCK - 1), 1);
+        area.deallocate(page(BITS_PER_BLOCK), 1);
+        assert_eq!(area.allocate(BITS_PER_BLOCK + 1), Some(page(BITS_PER_BLOCK - 1)));
+    }
+
+    #[test]
+    fn run_ends_at_area_end() {
+        let mut bitmap = [0usize; 2];
+        let mut area = AreaAllocator::new(&mut bitmap, page(0), 100);
+        assert_eq!(area.allocate(90), Some(page(0)));
+        // the bitmap has room for more pages, they are not part of the area
+        assert_eq!(area.allocate(11), None);
+        assert_eq!(area.allocate(10), Some(page(90)));
+        assert_eq!(area.allocate(1), None);
+
+        area.deallocate(page(95), 5);
+        assert_eq!(area.largest_free_run(), 5);
+        assert_eq!(area.allocate(5), Some(page(95)));
+    }
+}
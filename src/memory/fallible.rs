@@ -0,0 +1,52 @@
+use core::alloc::{AllocErr, Layout};
+use core::cmp;
+use core::mem;
+use core::ptr;
+
+use alloc::alloc::alloc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Like `Box::new`, but returns an error instead of panicking if the heap is exhausted
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocErr> {
+    if mem::size_of::<T>() == 0 {
+        return Ok(Box::new(value));
+    }
+
+    unsafe {
+        let ptr = alloc(Layout::new::<T>()) as *mut T;
+        if ptr.is_null() {
+            return Err(AllocErr);
+        }
+        ptr::write(ptr, value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Like `Vec::with_capacity`, but returns an error instead of panicking if the heap is exhausted
+pub fn try_vec<T>(capacity: usize) -> Result<Vec<T>, AllocErr> {
+    if capacity == 0 || mem::size_of::<T>() == 0 {
+        return Ok(Vec::with_capacity(capacity));
+    }
+
+    let layout = Layout::array::<T>(capacity).map_err(|_| AllocErr)?;
+    unsafe {
+        let ptr = alloc(layout) as *mut T;
+        if ptr.is_null() {
+            return Err(AllocErr);
+        }
+        Ok(Vec::from_raw_parts(ptr, 0, capacity))
+    }
+}
+
+/// Like `Vec::push`, but leaves `vec` untouched and returns an error if growing it fails
+pub fn try_push<T>(vec: &mut Vec<T>, value: T) -> Result<(), AllocErr> {
+    if vec.len() == vec.capacity() {
+        let mut grown = try_vec(cmp::max(4, vec.capacity() * 2))?;
+        // fits into the new capacity, so extending doesn't allocate
+        grown.extend(vec.drain(..));
+        *vec = grown;
+    }
+    vec.push(value);
+    Ok(())
+}
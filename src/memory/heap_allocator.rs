@@ -1,44 +1,444 @@
 use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
+use core::mem;
 use core::ptr::NonNull;
-use spin::Mutex;
-use slab_allocator::Heap;
+use sync::IrqMutex;
+
+use memory::allocate_frame;
+use memory::shrinker;
+#[cfg(feature = "heap_debug")]
+use memory::heap_debug;
+use memory::area_allocator::AreaAllocator;
+use memory::slab::{self, Slabs, SlabPage, SLAB_CLASSES, UNUSED_SLAB_PAGE};
+use memory::paging::{self, Page, ActivePageTable, EntryFlags, PAGE_SIZE};
 
 pub const HEAP_START: usize = 0o_000_001_000_000_0000;
-pub const HEAP_SIZE: usize = 80 * 4096; // 320 KiB
+/// Virtual memory reserved for the heap, it is mapped as the heap grows
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+
+/// The lower half of the reserved memory holds the slabs, one page each.
+/// A page is mapped when a size class runs out of objects, and unmapped and
+/// its frame freed once all of its objects are free again.
+const SLAB_AREA_END: usize = HEAP_START + HEAP_MAX_SIZE / 2;
+const SLAB_AREA_PAGES: usize = HEAP_MAX_SIZE / 2 / PAGE_SIZE;
+const SLAB_AREA_BITMAP_SIZE: usize = SLAB_AREA_PAGES / (mem::size_of::<usize>() * 8);
+
+static mut SLAB_AREA_BITMAP: [usize; SLAB_AREA_BITMAP_SIZE] = [0; SLAB_AREA_BITMAP_SIZE];
+static mut SLAB_PAGES: [SlabPage; SLAB_AREA_PAGES] = [UNUSED_SLAB_PAGE; SLAB_AREA_PAGES];
+
+/// Empty pages each size class keeps mapped, so an object that is allocated
+/// and freed over and over does not map and unmap a page every time
+const KEEP_EMPTY_SLAB_PAGES: usize = 1;
 
+/// The upper half is handed out page by page to allocations too big for the slabs,
+/// the pages are unmapped and their frames freed when the allocation is freed
+const LARGE_AREA_START: usize = SLAB_AREA_END;
+const LARGE_AREA_PAGES: usize = HEAP_MAX_SIZE / 2 / PAGE_SIZE;
+const LARGE_AREA_BITMAP_SIZE: usize = LARGE_AREA_PAGES / (mem::size_of::<usize>() * 8);
 
-static HEAP: Mutex<Option<Heap>> = Mutex::new(None);
+static mut LARGE_AREA_BITMAP: [usize; LARGE_AREA_BITMAP_SIZE] = [0; LARGE_AREA_BITMAP_SIZE];
 
-pub unsafe fn init(offset: usize, size: usize) {
-    *HEAP.lock() = Some(Heap::new(offset, size));
+/// Large allocations whose pages can wait for the active table to be unlocked
+const PENDING_UNMAPS: usize = 16;
+
+/// The seven slab sizes from 64 B to 4 KiB and allocations too big for them
+pub const SIZE_CLASSES: usize = SLAB_CLASSES + 1;
+const LARGE_CLASS: usize = SLAB_CLASSES;
+const SIZE_CLASS_NAMES: [&str; SIZE_CLASSES] = ["64 B", "128 B", "256 B", "512 B", "1 KiB", "2 KiB", "4 KiB", "large"];
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    pub allocations: usize,
+    pub frees: usize,
+    pub failed_allocations: usize,
+    /// Bytes requested by live allocations
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    /// Bytes of the blocks backing live allocations, the difference
+    /// to `bytes_in_use` is lost to rounding up to the block size
+    pub reserved_bytes: usize,
 }
 
-pub struct Allocator;
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub classes: [SizeClassStats; SIZE_CLASSES],
+    pub bytes_in_use: usize,
+    pub peak_bytes_in_use: usize,
+    /// Mapped part of the slab area
+    pub slab_area_size: usize,
+    /// Mapped slab pages whose objects are all free, see `KEEP_EMPTY_SLAB_PAGES`
+    pub empty_slab_pages: usize,
+    pub large_area_free_pages: usize,
+    /// Largest allocation the large area can still satisfy, in pages
+    pub large_area_largest_free_run: usize,
+    /// Pages of large allocations freed while the active table was locked
+    /// and too many others were waiting to be unmapped, they are never reused
+    pub leaked_large_pages: usize,
+}
 
-unsafe impl GlobalAlloc for Allocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if let Some(ref mut heap) = *HEAP.lock() {
-            heap.allocate(layout).ok().map_or(0 as *mut u8, |allocation| {
-                allocation.as_ptr()
-            })
+impl HeapStats {
+    fn record_allocation(&mut self, class: usize, size: usize, reserved: usize) {
+        {
+            let stats = &mut self.classes[class];
+            stats.allocations += 1;
+            stats.bytes_in_use += size;
+            stats.reserved_bytes += reserved;
+            if stats.bytes_in_use > stats.peak_bytes_in_use {
+                stats.peak_bytes_in_use = stats.bytes_in_use;
+            }
+        }
+        self.bytes_in_use += size;
+        if self.bytes_in_use > self.peak_bytes_in_use {
+            self.peak_bytes_in_use = self.bytes_in_use;
+        }
+    }
+
+    fn record_free(&mut self, class: usize, size: usize, reserved: usize) {
+        {
+            let stats = &mut self.classes[class];
+            stats.frees += 1;
+            stats.bytes_in_use -= size;
+            stats.reserved_bytes -= reserved;
+        }
+        self.bytes_in_use -= size;
+    }
+}
+
+struct KernelHeap {
+    slabs: Slabs<'static>,
+    /// Hands out the pages of the slab area
+    slab_area: AreaAllocator<'static>,
+    /// Mapped pages of the slab area
+    slab_pages: usize,
+    large_area: AreaAllocator<'static>,
+    /// Large allocations freed while the active table was locked, still mapped
+    pending_unmaps: [Option<(Page, usize)>; PENDING_UNMAPS],
+    stats: HeapStats,
+}
+
+static HEAP: IrqMutex<Option<KernelHeap>> = IrqMutex::new(None);
+
+/// Set up the allocator, pages are mapped as they are needed.
+/// Must be called once, and only once, after the kernel is remapped.
+pub unsafe fn init() {
+    let heap_start_page = Page::containing_address(HEAP_START);
+    *HEAP.lock() = Some(KernelHeap {
+        slabs: Slabs::new(heap_start_page, &mut SLAB_PAGES),
+        slab_area: AreaAllocator::new(&mut SLAB_AREA_BITMAP, heap_start_page, SLAB_AREA_PAGES),
+        slab_pages: 0,
+        large_area: AreaAllocator::new(&mut LARGE_AREA_BITMAP,
+                                       Page::containing_address(LARGE_AREA_START),
+                                       LARGE_AREA_PAGES),
+        pending_unmaps: [None; PENDING_UNMAPS],
+        stats: HeapStats::default(),
+    });
+}
+
+/// Map fresh frames to `count` pages starting at `start`.
+/// Nothing stays mapped if we run out of frames.
+fn map_pages(active_table: &mut ActivePageTable, start: Page, count: usize) -> bool {
+    for i in 0..count {
+        match allocate_frame() {
+            Some(frame) => {
+                let result = active_table.map_to(start + i, frame, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+                result.flush(active_table);
+            }
+            None => {
+                unmap_pages(active_table, start, i);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn unmap_pages(active_table: &mut ActivePageTable, start: Page, count: usize) {
+    for i in 0..count {
+        let result = active_table.unmap(start + i);
+        result.flush(active_table);
+    }
+}
+
+fn size_in_pages(layout: &Layout) -> usize {
+    (layout.size() + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+/// Smallest slab whose objects fit the allocation, objects are aligned
+/// to their size
+fn size_class(layout: &Layout) -> usize {
+    let size = cmp::max(layout.size(), layout.align());
+    (0..SLAB_CLASSES).find(|&class| slab::object_size(class) >= size)
+                     .unwrap_or(LARGE_CLASS)
+}
+
+impl KernelHeap {
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let allocation = self.allocate_block(layout.clone());
+        if allocation.is_some() {
+            let class = size_class(&layout);
+            let reserved = self.reserved_size(class, &layout);
+            self.stats.record_allocation(class, layout.size(), reserved);
+        }
+        allocation
+    }
+
+    fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let class = size_class(&layout);
+        let reserved = self.reserved_size(class, &layout);
+        self.stats.record_free(class, layout.size(), reserved);
+        self.deallocate_block(ptr, layout);
+    }
+
+    /// Size of the block backing an allocation
+    fn reserved_size(&self, class: usize, layout: &Layout) -> usize {
+        if class == LARGE_CLASS {
+            size_in_pages(layout) * PAGE_SIZE
         } else {
-            panic!("__rust_allocate: heap not initialized");
+            slab::object_size(class)
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        if let Some(ref mut heap) = *HEAP.lock() {
-            heap.deallocate(NonNull::new_unchecked(ptr), layout)
+    fn stats(&self) -> HeapStats {
+        let mut stats = self.stats;
+        stats.slab_area_size = self.slab_pages * PAGE_SIZE;
+        stats.empty_slab_pages = self.slabs.empty_pages();
+        stats.large_area_free_pages = self.large_area.free_pages();
+        stats.large_area_largest_free_run = self.large_area.largest_free_run();
+        stats
+    }
+
+    fn allocate_block(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let class = size_class(&layout);
+        if class == LARGE_CLASS {
+            return self.allocate_large(&layout);
+        }
+
+        if let Some(allocation) = self.slabs.allocate(class) {
+            return Some(allocation);
+        }
+        if self.add_slab_page(class) {
+            self.slabs.allocate(class)
+        } else {
+            None
+        }
+    }
+
+    fn deallocate_block(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if self.large_area.contains(ptr.as_ptr() as usize) {
+            self.deallocate_large(ptr, &layout);
         } else {
-            panic!("__rust_deallocate: heap not initialized");
+            unsafe { self.slabs.deallocate(ptr, size_class(&layout)); }
+            if self.slabs.has_empty_pages(KEEP_EMPTY_SLAB_PAGES) {
+                self.release_slab_pages(KEEP_EMPTY_SLAB_PAGES);
+            }
         }
     }
 
+    /// Map another page of the slab area and give it to `class`
+    fn add_slab_page(&mut self, class: usize) -> bool {
+        let page = match self.slab_area.allocate(1) {
+            Some(page) => page,
+            None => return false,
+        };
+
+        if !self.map_pages(page, 1) {
+            self.slab_area.deallocate(page, 1);
+            return false;
+        }
+        unsafe { self.slabs.add_page(page, class); }
+        self.slab_pages += 1;
+        true
+    }
+
+    /// Unmap empty slab pages until no size class has more than `keep` of
+    /// them. Returns the number of unmapped pages. If the active table is
+    /// locked the pages stay mapped, until the next free or shrink.
+    fn release_slab_pages(&mut self, keep: usize) -> usize {
+        let slabs = &mut self.slabs;
+        let slab_area = &mut self.slab_area;
+        let released = paging::try_with_active_table(|active_table| {
+            let mut released = 0;
+            while let Some(page) = slabs.take_empty_page(keep) {
+                unmap_pages(active_table, page, 1);
+                slab_area.deallocate(page, 1);
+                released += 1;
+            }
+            released
+        });
+        let released = released.unwrap_or(0);
+        self.slab_pages -= released;
+        released
+    }
+
+    fn allocate_large(&mut self, layout: &Layout) -> Option<NonNull<u8>> {
+        if layout.align() > PAGE_SIZE {
+            return None;
+        }
+
+        let count = size_in_pages(layout);
+        let start = match self.large_area.allocate(count) {
+            Some(start) => start,
+            None => return None,
+        };
+
+        if self.map_pages(start, count) {
+            NonNull::new(start.start_address() as *mut u8)
+        } else {
+            self.large_area.deallocate(start, count);
+            None
+        }
+    }
+
+    fn deallocate_large(&mut self, ptr: NonNull<u8>, layout: &Layout) {
+        let start = Page::containing_address(ptr.as_ptr() as usize);
+        let count = size_in_pages(layout);
+
+        let pending_unmaps = &mut self.pending_unmaps;
+        let large_area = &mut self.large_area;
+        let unmapped = paging::try_with_active_table(|active_table| {
+            unmap_pages(active_table, start, count);
+        });
+        if unmapped.is_some() {
+            large_area.deallocate(start, count);
+            return;
+        }
+
+        // the pages stay reserved until they are unmapped
+        match pending_unmaps.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some((start, count)),
+            None => self.stats.leaked_large_pages += count,
+        }
+    }
+
+    /// Map fresh frames to `count` pages starting at `start`, and unmap the
+    /// pages of large allocations freed while the active table was locked.
+    /// Fails if the table is locked now, see `paging::ACTIVE_TABLE`.
+    fn map_pages(&mut self, start: Page, count: usize) -> bool {
+        let pending_unmaps = &mut self.pending_unmaps;
+        let large_area = &mut self.large_area;
+        let mapped = paging::try_with_active_table(|active_table| {
+            for slot in pending_unmaps.iter_mut() {
+                if let Some((pending_start, pending_count)) = slot.take() {
+                    unmap_pages(active_table, pending_start, pending_count);
+                    large_area.deallocate(pending_start, pending_count);
+                }
+            }
+            map_pages(active_table, start, count)
+        });
+        mapped == Some(true)
+    }
+}
+
+fn allocate(layout: Layout) -> *mut u8 {
+    if let Some(ref mut heap) = *HEAP.lock() {
+        heap.allocate(layout).map_or(0 as *mut u8, |allocation| {
+            allocation.as_ptr()
+        })
+    } else {
+        panic!("__rust_allocate: heap not initialized");
+    }
+}
+
+/// Returns null only after the heap could not grow and the shrinkers
+/// could not release enough memory
+pub fn allocate_or_shrink(layout: Layout) -> *mut u8 {
+    let mut ptr = allocate(layout.clone());
+    if ptr.is_null() && shrinker::shrink_all() > 0 {
+        ptr = allocate(layout.clone());
+    }
+    if ptr.is_null() {
+        if let Some(ref mut heap) = *HEAP.lock() {
+            heap.stats.classes[size_class(&layout)].failed_allocations += 1;
+        }
+    }
+    ptr
+}
+
+pub unsafe fn deallocate(ptr: *mut u8, layout: Layout) {
+    if let Some(ref mut heap) = *HEAP.lock() {
+        heap.deallocate(NonNull::new_unchecked(ptr), layout)
+    } else {
+        panic!("__rust_deallocate: heap not initialized");
+    }
+}
+
+/// Whether an allocation gets pages of its own, which are unmapped when it is freed
+pub fn is_large(layout: &Layout) -> bool {
+    size_class(layout) == LARGE_CLASS
+}
+
+/// Shrinker that unmaps the empty slab pages the size classes keep for reuse
+pub fn release_empty_slab_pages() -> usize {
+    let mut heap = match HEAP.try_lock() {
+        Some(heap) => heap,
+        None => return 0,
+    };
+    let released = match *heap {
+        Some(ref mut heap) => heap.release_slab_pages(0),
+        None => 0,
+    };
+    released * PAGE_SIZE
+}
+
+/// Snapshot of the heap statistics
+pub fn stats() -> HeapStats {
+    if let Some(ref heap) = *HEAP.lock() {
+        heap.stats()
+    } else {
+        panic!("heap stats: heap not initialized");
+    }
+}
+
+/// Print the heap statistics per size class together with the internal
+/// fragmentation of each class and the fragmentation of the large area
+pub fn print_stats() {
+    let stats = stats();
+    println!("heap: in use: {} KiB, peak: {} KiB, slab area: {} KiB, empty slab pages: {}",
+             stats.bytes_in_use / 1024, stats.peak_bytes_in_use / 1024, stats.slab_area_size / 1024,
+             stats.empty_slab_pages);
+    for (name, class) in SIZE_CLASS_NAMES.iter().zip(stats.classes.iter()) {
+        println!("  {:>5}: allocs: {}, frees: {}, failed: {}, in use: {} B, peak: {} B, wasted: {} B",
+                 name, class.allocations, class.frees, class.failed_allocations,
+                 class.bytes_in_use, class.peak_bytes_in_use,
+                 class.reserved_bytes - class.bytes_in_use);
+    }
+    println!("  large area: free: {} KiB, largest free run: {} KiB, leaked: {} KiB",
+             stats.large_area_free_pages * PAGE_SIZE / 1024,
+             stats.large_area_largest_free_run * PAGE_SIZE / 1024,
+             stats.leaked_large_pages * PAGE_SIZE / 1024);
+}
+
+pub struct Allocator;
+
+#[cfg(not(feature = "heap_debug"))]
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        allocate_or_shrink(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        deallocate(ptr, layout)
+    }
+
+}
+
+/// Wraps every allocation in redzones and keeps track of it, see `heap_debug`
+#[cfg(feature = "heap_debug")]
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        heap_debug::alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        heap_debug::dealloc(ptr, layout)
+    }
+
 }
 
 #[cfg(not(test))]
 #[alloc_error_handler]
 #[no_mangle]
-pub extern "C" fn oom(_: ::core::alloc::Layout) -> ! {
-    panic!("kernel memory allocation failed");
+pub extern "C" fn oom(layout: ::core::alloc::Layout) -> ! {
+    // the allocator already tried to grow the heap and run the shrinkers
+    panic!("kernel memory allocation failed: size: {:#x}, align: {:#x}", layout.size(), layout.align());
 }
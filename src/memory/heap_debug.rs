@@ -0,0 +1,293 @@
+//! Debug heap, enabled with the `heap_debug` feature.
+//!
+//! Every allocation gets a header and a redzone on both sides. Redzones are
+//! checked and the memory is poisoned on free. Freed blocks wait in a
+//! quarantine before they go back to the heap, which catches double frees and
+//! writes after free. The pages of large blocks are unmapped once they leave
+//! the quarantine, their ranges are remembered for a while to report double
+//! frees without touching the header. Live allocations are kept in a list together with the
+//! return addresses of their callers, `dump_allocations` prints it over serial.
+//! The addresses can be resolved with `addr2line -e build/kernel-x86_64.bin`.
+
+use core::alloc::Layout;
+use core::cmp;
+use core::mem;
+use core::ptr;
+
+use sync::IrqMutex;
+
+use memory::heap_allocator::{allocate_or_shrink, deallocate, is_large};
+
+const REDZONE_SIZE: usize = 16;
+const REDZONE_BYTE: u8 = 0xfd;
+/// Fresh allocations are filled with this to make uninitialized reads visible
+const ALLOC_POISON_BYTE: u8 = 0xa5;
+/// Freed memory is filled with this
+const FREE_POISON_BYTE: u8 = 0x6b;
+
+const MAGIC_LIVE: usize = 0x4c49_5645_4845_4150;
+const MAGIC_FREED: usize = 0x4652_4545_4845_4150;
+
+/// Return addresses recorded for every allocation
+const CALLER_DEPTH: usize = 6;
+/// Frames of the allocator itself that are not recorded
+const SKIPPED_FRAMES: usize = 2;
+
+const QUARANTINE_SIZE: usize = 32;
+/// Large blocks remembered after they went back to the heap
+const RELEASED_SIZE: usize = 32;
+
+#[repr(C)]
+struct Header {
+    /// Overwritten by the free lists of the heap once the block is released
+    reserved: [usize; 2],
+    magic: usize,
+    size: usize,
+    align: usize,
+    callers: [usize; CALLER_DEPTH],
+    prev: *mut Header,
+    next: *mut Header,
+}
+
+struct DebugHeap {
+    /// Live allocations, newest first
+    head: *mut Header,
+    live_allocations: usize,
+    live_bytes: usize,
+    /// Freed blocks not yet returned to the heap, with their user pointers
+    quarantine: [Option<(*mut u8, Layout)>; QUARANTINE_SIZE],
+    next_quarantine_slot: usize,
+    /// Start and end of large blocks returned to the heap, their pages may be unmapped
+    released: [Option<(usize, usize)>; RELEASED_SIZE],
+    next_released_slot: usize,
+}
+
+impl DebugHeap {
+    fn record_released(&mut self, start: usize, end: usize) {
+        let slot = self.next_released_slot;
+        self.next_released_slot = (slot + 1) % RELEASED_SIZE;
+        self.released[slot] = Some((start, end));
+    }
+
+    /// Forget released blocks the heap handed out again
+    fn forget_released(&mut self, start: usize, end: usize) {
+        for slot in self.released.iter_mut() {
+            if let Some((released_start, released_end)) = *slot {
+                if released_start < end && start < released_end {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    fn was_released(&self, address: usize) -> bool {
+        self.released.iter().any(|slot| match *slot {
+            Some((start, end)) => address >= start && address < end,
+            None => false,
+        })
+    }
+}
+
+// the raw pointers are only touched with the lock held
+unsafe impl Send for DebugHeap {}
+
+static DEBUG_HEAP: IrqMutex<DebugHeap> = IrqMutex::new(DebugHeap {
+    head: 0 as *mut Header,
+    live_allocations: 0,
+    live_bytes: 0,
+    quarantine: [None; QUARANTINE_SIZE],
+    next_quarantine_slot: 0,
+    released: [None; RELEASED_SIZE],
+    next_released_slot: 0,
+});
+
+/// Layout of the whole block and the offset of the user pointer in it
+fn block_layout(layout: &Layout) -> (Layout, usize) {
+    let align = cmp::max(layout.align(), mem::align_of::<Header>());
+    let prefix = round_up(mem::size_of::<Header>() + REDZONE_SIZE, align);
+    let size = prefix + layout.size() + REDZONE_SIZE;
+    (Layout::from_size_align(size, align).expect("heap debug: invalid layout"), prefix)
+}
+
+fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    ptr.offset(-((REDZONE_SIZE + mem::size_of::<Header>()) as isize)) as *mut Header
+}
+
+/// Return addresses of the callers, found by following the frame pointers
+#[inline(never)]
+fn callers() -> [usize; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    let mut frame: usize;
+    unsafe { asm!("mov %rbp, $0" : "=r"(frame) ::: "volatile"); }
+
+    for i in 0..SKIPPED_FRAMES + CALLER_DEPTH {
+        // the boot code starts the chain with a null frame pointer
+        if frame == 0 || frame % mem::align_of::<usize>() != 0 {
+            break;
+        }
+        let (next_frame, return_address) = unsafe {
+            (*(frame as *const usize), *((frame + 8) as *const usize))
+        };
+        if i >= SKIPPED_FRAMES {
+            callers[i - SKIPPED_FRAMES] = return_address;
+        }
+        // stacks grow down, so the chain has to move up
+        if next_frame <= frame {
+            break;
+        }
+        frame = next_frame;
+    }
+    callers
+}
+
+pub unsafe fn alloc(layout: Layout) -> *mut u8 {
+    let (block, prefix) = block_layout(&layout);
+    let raw = allocate_or_shrink(block);
+    if raw.is_null() {
+        return raw;
+    }
+
+    let ptr = raw.offset(prefix as isize);
+    ptr::write_bytes(ptr.offset(-(REDZONE_SIZE as isize)), REDZONE_BYTE, REDZONE_SIZE);
+    ptr::write_bytes(ptr, ALLOC_POISON_BYTE, layout.size());
+    ptr::write_bytes(ptr.offset(layout.size() as isize), REDZONE_BYTE, REDZONE_SIZE);
+
+    let header = header(ptr);
+    let mut heap = DEBUG_HEAP.lock();
+    heap.forget_released(raw as usize, raw as usize + block.size());
+    ptr::write(header, Header {
+        reserved: [0; 2],
+        magic: MAGIC_LIVE,
+        size: layout.size(),
+        align: layout.align(),
+        callers: callers(),
+        prev: ptr::null_mut(),
+        next: heap.head,
+    });
+    if !heap.head.is_null() {
+        (*heap.head).prev = header;
+    }
+    heap.head = header;
+    heap.live_allocations += 1;
+    heap.live_bytes += layout.size();
+    ptr
+}
+
+pub unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
+    let header = header(ptr);
+    let mut heap = DEBUG_HEAP.lock();
+
+    if heap.was_released(ptr as usize) {
+        fail(ptr, &layout, "double free of a block already returned to the heap");
+    }
+    match (*header).magic {
+        MAGIC_LIVE => {},
+        MAGIC_FREED => report(ptr, &layout, "double free"),
+        _ => report(ptr, &layout, "free of a pointer not allocated by the heap or header corrupted"),
+    }
+    if (*header).size != layout.size() || (*header).align != layout.align() {
+        serial_println!("heap debug: allocated with size: {:#x}, align: {:#x}",
+                        (*header).size, (*header).align);
+        report(ptr, &layout, "layout does not match the allocation");
+    }
+    check_redzones(ptr, &layout);
+
+    // unlink from the live list
+    if (*header).prev.is_null() {
+        heap.head = (*header).next;
+    } else {
+        (*(*header).prev).next = (*header).next;
+    }
+    if !(*header).next.is_null() {
+        (*(*header).next).prev = (*header).prev;
+    }
+    heap.live_allocations -= 1;
+    heap.live_bytes -= layout.size();
+
+    (*header).magic = MAGIC_FREED;
+    ptr::write_bytes(ptr, FREE_POISON_BYTE, layout.size());
+
+    // park the block, release the one it replaces
+    let slot = heap.next_quarantine_slot;
+    heap.next_quarantine_slot = (slot + 1) % QUARANTINE_SIZE;
+    let evicted = mem::replace(&mut heap.quarantine[slot], Some((ptr, layout)));
+    drop(heap);
+
+    if let Some((evicted_ptr, evicted_layout)) = evicted {
+        check_poison(evicted_ptr, &evicted_layout);
+        let (block, prefix) = block_layout(&evicted_layout);
+        let raw = evicted_ptr.offset(-(prefix as isize));
+        if is_large(&block) {
+            DEBUG_HEAP.lock().record_released(raw as usize, raw as usize + block.size());
+        }
+        deallocate(raw, block);
+    }
+}
+
+unsafe fn check_redzones(ptr: *mut u8, layout: &Layout) {
+    for i in 0..REDZONE_SIZE {
+        if *ptr.offset(i as isize - REDZONE_SIZE as isize) != REDZONE_BYTE {
+            serial_println!("heap debug: byte {} before the allocation overwritten", REDZONE_SIZE - i);
+            report(ptr, layout, "redzone before the allocation corrupted");
+        }
+        if *ptr.offset((layout.size() + i) as isize) != REDZONE_BYTE {
+            serial_println!("heap debug: byte {} after the allocation overwritten", i);
+            report(ptr, layout, "redzone after the allocation corrupted");
+        }
+    }
+}
+
+/// Freed memory must still hold the poison when it leaves the quarantine
+unsafe fn check_poison(ptr: *mut u8, layout: &Layout) {
+    for i in 0..layout.size() {
+        if *ptr.offset(i as isize) != FREE_POISON_BYTE {
+            serial_println!("heap debug: byte {:#x} written after free", i);
+            report(ptr, layout, "write after free");
+        }
+    }
+}
+
+unsafe fn report(ptr: *mut u8, layout: &Layout, problem: &str) -> ! {
+    serial_println!("heap debug: {} at {:#x}, size: {:#x}, align: {:#x}",
+                    problem, ptr as usize, layout.size(), layout.align());
+    let header = header(ptr);
+    if (*header).magic == MAGIC_LIVE || (*header).magic == MAGIC_FREED {
+        print_callers(&(*header).callers);
+    }
+    panic!("heap debug: {} at {:#x}", problem, ptr as usize);
+}
+
+/// Like `report`, for blocks whose header may not be mapped any more
+fn fail(ptr: *mut u8, layout: &Layout, problem: &str) -> ! {
+    serial_println!("heap debug: {} at {:#x}, size: {:#x}, align: {:#x}",
+                    problem, ptr as usize, layout.size(), layout.align());
+    panic!("heap debug: {} at {:#x}", problem, ptr as usize);
+}
+
+fn print_callers(callers: &[usize; CALLER_DEPTH]) {
+    for &caller in callers.iter().take_while(|&&caller| caller != 0) {
+        serial_println!("    called from {:#x}", caller);
+    }
+}
+
+/// Print every live allocation with its callers over serial.
+/// Allocations still live at a point where they should be gone are leaks.
+pub fn dump_allocations() {
+    let heap = DEBUG_HEAP.lock();
+    serial_println!("heap debug: {} live allocations, {} bytes",
+                    heap.live_allocations, heap.live_bytes);
+
+    let mut header = heap.head;
+    while !header.is_null() {
+        unsafe {
+            let ptr = (header as usize) + mem::size_of::<Header>() + REDZONE_SIZE;
+            serial_println!("  {:#x}: size: {:#x}, align: {:#x}", ptr, (*header).size, (*header).align);
+            print_callers(&(*header).callers);
+            header = (*header).next;
+        }
+    }
+}
@@ -1,29 +1,43 @@
 pub mod paging;
 pub mod heap_allocator;
+pub mod working_set;
+pub mod protection_keys;
+pub mod shrinker;
+pub mod fallible;
+pub mod object_cache;
+pub mod vmalloc;
+pub mod physical_map;
+#[cfg(feature = "heap_debug")]
+pub mod heap_debug;
 
 mod bitmap_frame_allocator;
+mod area_allocator;
+mod slab;
 mod stack_allocator;
+mod shared_memory;
 
 use self::bitmap_frame_allocator::BitmapFrameAllocator;
 
-use self::paging::{PAGE_SIZE, PhysicalAddress, Page, ActivePageTable};
+use self::paging::{PAGE_SIZE, PhysicalAddress, Page, InactivePageTable, TemporaryPage, EntryFlags};
 
-use self::heap_allocator::{HEAP_START, HEAP_SIZE};
+use self::heap_allocator::{HEAP_START, HEAP_MAX_SIZE};
 
 use x86_64::registers::model_specific::{Efer, EferFlags};
 use x86_64::registers::control::{Cr0, Cr0Flags};
 
-use spin::Mutex;
+use sync::IrqMutex;
 
 use multiboot2::{MemoryAreaIter, ElfSectionsTag, MemoryMapTag, BootInformation};
 
-pub use self::stack_allocator::Stack;
-
-use self::stack_allocator::StackAllocator;
+pub use self::stack_allocator::{Stack, StackInfo, stack_for_guard_page, print_stack_usage};
+pub use self::shared_memory::{SharedMemory, SharedMapping};
 
 const STACK_ALLOCATOR_PAGES: usize = 100;
 
-static ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+/// Pages the working set shrinker tries to free at once
+const RECLAIM_BATCH_PAGES: usize = 64;
+
+static ALLOCATOR: IrqMutex<Option<BitmapFrameAllocator>> = IrqMutex::new(None);
 
 /// Init memory allocator
 /// Must be called once, and only once,
@@ -87,14 +101,80 @@ pub trait FrameAllocator {
     fn deallocate_frame(&mut self, frame: Frame);
 }
 
+/// Owns the temporary page used to edit inactive tables, the active table
+/// itself is shared with the heap, see `paging::with_active_table`
 pub struct MemoryController {
-    active_table: ActivePageTable,
-    stack_allocator: StackAllocator,
+    temporary_page: TemporaryPage,
 }
 
 impl MemoryController {
-    pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
-        self.stack_allocator.alloc_stack(&mut self.active_table, size_in_pages)
+    /// Allocate a stack with a guard page below it. `owner` is reported
+    /// if the stack overflows into the guard page.
+    pub fn alloc_stack(&mut self, size_in_pages: usize, owner: &'static str) -> Option<Stack> {
+        stack_allocator::alloc_stack(size_in_pages, owner)
+    }
+
+    /// Sample the accessed bits of all pages in tracked working set regions
+    pub fn scan_working_sets(&mut self) {
+        paging::with_active_table(|active_table| working_set::scan(active_table))
+    }
+
+    /// Free up to `target` clean pages that were not used recently.
+    /// Returns the number of freed pages.
+    pub fn reclaim_pages(&mut self, target: usize) -> usize {
+        paging::with_active_table(|active_table| working_set::reclaim(active_table, target))
+    }
+
+    /// Assign a protection key to mapped pages, see `protection_keys`
+    pub fn protect_pages(&mut self, pages: paging::PageIter, key: &protection_keys::ProtectionKey) {
+        paging::with_active_table(|active_table| protection_keys::protect(active_table, pages, key))
+    }
+
+    /// Switch mapped pages back to the default protection key
+    pub fn unprotect_pages(&mut self, pages: paging::PageIter) {
+        paging::with_active_table(|active_table| protection_keys::unprotect(active_table, pages))
+    }
+
+    /// Create a new page table with only the recursive mapping set up
+    pub fn new_page_table(&mut self) -> Option<InactivePageTable> {
+        let temporary_page = &mut self.temporary_page;
+        allocate_frame().map(|frame| {
+            paging::with_active_table(|active_table| {
+                InactivePageTable::new(frame, active_table, temporary_page)
+            })
+        })
+    }
+
+    /// Allocate a shared memory object of `size_in_pages` zeroed frames
+    pub fn alloc_shared_memory(&mut self, size_in_pages: usize) -> Option<SharedMemory> {
+        SharedMemory::new(size_in_pages, &mut self.temporary_page)
+    }
+
+    /// Map a shared memory object into the active table
+    pub fn map_shared(&mut self, memory: &SharedMemory, start: Page, flags: EntryFlags) -> SharedMapping {
+        paging::with_active_table(|active_table| memory.map(active_table, start, flags))
+    }
+
+    /// Map a shared memory object into an inactive table
+    pub fn map_shared_inactive(&mut self, memory: &SharedMemory, table: &mut InactivePageTable,
+                               start: Page, flags: EntryFlags) -> SharedMapping {
+        let temporary_page = &mut self.temporary_page;
+        paging::with_active_table(|active_table| {
+            memory.map_inactive(active_table, table, temporary_page, start, flags)
+        })
+    }
+
+    /// Unmap a shared memory object from the active table
+    pub fn unmap_shared(&mut self, mapping: SharedMapping) {
+        paging::with_active_table(|active_table| mapping.unmap(active_table))
+    }
+
+    /// Unmap a shared memory object from an inactive table
+    pub fn unmap_shared_inactive(&mut self, mapping: SharedMapping, table: &mut InactivePageTable) {
+        let temporary_page = &mut self.temporary_page;
+        paging::with_active_table(|active_table| {
+            mapping.unmap_inactive(active_table, table, temporary_page)
+        })
     }
 }
 
@@ -149,7 +229,17 @@ pub fn print_kernel_sections(elf_sections_tag: &'static ElfSectionsTag) {
     }
 }
 
+/// Shrinker that frees clean pages of reclaimable regions that were not used recently
+fn reclaim_inactive_pages() -> usize {
+    let freed = paging::try_with_active_table(|active_table| {
+        working_set::reclaim(active_table, RECLAIM_BATCH_PAGES)
+    });
+    freed.unwrap_or(0) * PAGE_SIZE
+}
+
 pub fn init(boot_info: &BootInformation) -> MemoryController {
+    paging::detect_five_level_paging();
+
     let memory_map_tag = boot_info.memory_map_tag().expect(
         "Memory map tag required");
     let elf_sections_tag = boot_info.elf_sections_tag().expect(
@@ -168,31 +258,36 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
              boot_info.start_address(),
              boot_info.end_address());
 
-    unsafe {frame_allocator_init(kernel_start as usize, kernel_end as usize, boot_info.start_address(), boot_info.end_address(), memory_map_tag.memory_areas());}
+    println!("paging levels: {}", if paging::five_level_paging() { 5 } else { 4 });
 
-    let mut active_table = paging::remap_the_kernel(boot_info);
+    unsafe {frame_allocator_init(kernel_start as usize, kernel_end as usize, boot_info.start_address(), boot_info.end_address(), memory_map_tag.memory_areas());}
 
-    let heap_start_page = Page::containing_address(HEAP_START);
-    let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE - 1);
+    paging::remap_the_kernel(boot_info);
+    paging::with_active_table(|active_table| stack_allocator::unmap_boot_stack_guard_page(active_table));
 
-    for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        let result = active_table.map(page, paging::EntryFlags::WRITABLE);
-        result.flush(&mut active_table);
+    if protection_keys::init() {
+        println!("protection keys enabled");
     }
 
-    unsafe {heap_allocator::init(HEAP_START, HEAP_SIZE);}
+    unsafe {heap_allocator::init();}
+    unsafe {vmalloc::init();}
 
-    let stack_allocator = {
+    shrinker::register_shrinker("working set", reclaim_inactive_pages);
+    shrinker::register_shrinker("heap slabs", heap_allocator::release_empty_slab_pages);
+
+    // the stacks are placed after the memory reserved for the heap
+    let heap_end_page = Page::containing_address(HEAP_START + HEAP_MAX_SIZE - 1);
+
+    {
         let stack_alloc_start = heap_end_page + 1;
         let stack_alloc_end = stack_alloc_start + STACK_ALLOCATOR_PAGES;
         let stack_alloc_range = Page::range_inclusive(stack_alloc_start,
                                                       stack_alloc_end);
-        StackAllocator::new(stack_alloc_range)
-    };
+        stack_allocator::init(stack_alloc_range);
+    }
 
     MemoryController {
-        active_table: active_table,
-        stack_allocator: stack_allocator,
+        temporary_page: TemporaryPage::new(paging::TEMPORARY_PAGE),
     }
 
 }
\ No newline at end of file
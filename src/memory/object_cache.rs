@@ -0,0 +1,364 @@
+use core::alloc::Layout;
+use core::cmp;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+
+use alloc::alloc::{alloc, dealloc};
+
+use spin::Mutex;
+
+use memory::paging::PAGE_SIZE;
+use memory::shrinker;
+
+/// A slab holds at least this many objects
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// Start of every slab, followed by the objects
+#[repr(C)]
+struct SlabHeader {
+    next: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+/// Link stored in the slot of a free object, in the object itself unless
+/// the cache has a constructor, see `link_offset`
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub allocations: usize,
+    pub frees: usize,
+    pub failed_allocations: usize,
+    pub objects_in_use: usize,
+    pub peak_objects_in_use: usize,
+    pub slabs: usize,
+}
+
+struct CacheInner {
+    slabs: *mut SlabHeader,
+    stats: CacheStats,
+}
+
+// the slabs are only touched with the lock held
+unsafe impl Send for CacheInner {}
+
+/// Cache of objects of a single type, carved out of dedicated slabs taken
+/// from the kernel heap. Freed objects are kept for reuse. Empty slabs are
+/// only returned to the heap by `shrink`, see `register_shrinker`.
+///
+/// If the cache has a constructor, every object of a slab is constructed
+/// when the slab is taken from the heap and dropped when it is returned.
+/// Objects keep their state in between, so they must be put back in their
+/// constructed state before they are freed.
+pub struct ObjectCache<T> {
+    name: &'static str,
+    constructor: Option<fn() -> T>,
+    inner: Mutex<CacheInner>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> ObjectCache<T> {
+    pub const fn new(name: &'static str, constructor: Option<fn() -> T>) -> ObjectCache<T> {
+        ObjectCache {
+            name: name,
+            constructor: constructor,
+            inner: Mutex::new(CacheInner {
+                slabs: 0 as *mut SlabHeader,
+                stats: CacheStats {
+                    allocations: 0,
+                    frees: 0,
+                    failed_allocations: 0,
+                    objects_in_use: 0,
+                    peak_objects_in_use: 0,
+                    slabs: 0,
+                },
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Move `value` into an object from the cache, replacing the
+    /// constructed object if the cache has a constructor
+    pub fn alloc(&self, value: T) -> Option<CacheBox<T>> {
+        let (slab, object) = match self.alloc_object() {
+            Some(allocation) => allocation,
+            None => return None,
+        };
+        unsafe {
+            if self.constructor.is_some() {
+                *object.as_ptr() = value;
+            } else {
+                ptr::write(object.as_ptr(), value);
+            }
+        }
+        Some(CacheBox {
+            cache: self,
+            slab: slab,
+            object: object,
+        })
+    }
+
+    /// Get a constructed object, as the previous user left it.
+    /// Returns None if the cache has no constructor or is out of memory.
+    pub fn alloc_constructed(&self) -> Option<CacheBox<T>> {
+        if self.constructor.is_none() {
+            return None;
+        }
+        self.alloc_object().map(|(slab, object)| {
+            CacheBox {
+                cache: self,
+                slab: slab,
+                object: object,
+            }
+        })
+    }
+
+    /// Let the heap shrink the cache when it runs out of memory
+    pub fn register_shrinker(&'static self) -> bool {
+        shrinker::register_shrinker_with(self.name, shrink_cache::<T>, self as *const ObjectCache<T> as usize)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().stats
+    }
+
+    pub fn print_stats(&self) {
+        let stats = self.stats();
+        println!("{}: in use: {} (peak {}), slabs: {}, allocations: {}, frees: {}, failed: {}",
+                 self.name, stats.objects_in_use, stats.peak_objects_in_use, stats.slabs,
+                 stats.allocations, stats.frees, stats.failed_allocations);
+    }
+
+    /// Return empty slabs to the heap. Returns the number of bytes released.
+    pub fn shrink(&self) -> usize {
+        let slabs = self.take_empty_slabs(&mut self.inner.lock());
+        self.release_slabs(slabs)
+    }
+
+    /// Unlink the empty slabs, returns them as a list
+    fn take_empty_slabs(&self, inner: &mut CacheInner) -> *mut SlabHeader {
+        let mut empty = ptr::null_mut();
+        let mut link: *mut *mut SlabHeader = &mut inner.slabs;
+        unsafe {
+            while !(*link).is_null() {
+                let slab = *link;
+                if (*slab).in_use == 0 {
+                    *link = (*slab).next;
+                    (*slab).next = empty;
+                    empty = slab;
+                    inner.stats.slabs -= 1;
+                } else {
+                    link = &mut (*slab).next;
+                }
+            }
+        }
+        empty
+    }
+
+    /// Drop the constructed objects of the slabs and return them to the heap.
+    /// Runs without the lock, the destructors may free memory.
+    fn release_slabs(&self, mut slab: *mut SlabHeader) -> usize {
+        let mut released = 0;
+        while !slab.is_null() {
+            unsafe {
+                let next = (*slab).next;
+                if self.constructor.is_some() {
+                    let first = (slab as usize) + ObjectCache::<T>::objects_offset();
+                    for i in 0..self.objects_per_slab() {
+                        ptr::drop_in_place((first + i * self.slot_size()) as *mut T);
+                    }
+                }
+                dealloc(slab as *mut u8, self.slab_layout());
+                released += self.slab_layout().size();
+                slab = next;
+            }
+        }
+        released
+    }
+
+    /// Offset of the free list link in a slot. Constructed objects keep
+    /// their state while they are free, so the link goes behind them.
+    fn link_offset(&self) -> usize {
+        if self.constructor.is_some() {
+            round_up(mem::size_of::<T>(), mem::align_of::<FreeObject>())
+        } else {
+            0
+        }
+    }
+
+    /// Size of an object slot, it has to be able to hold the free list link
+    fn slot_size(&self) -> usize {
+        let size = cmp::max(mem::size_of::<T>(), self.link_offset() + mem::size_of::<FreeObject>());
+        round_up(size, ObjectCache::<T>::slot_align())
+    }
+
+    fn slot_align() -> usize {
+        cmp::max(mem::align_of::<T>(), mem::align_of::<FreeObject>())
+    }
+
+    /// Offset of the first object in a slab
+    fn objects_offset() -> usize {
+        round_up(mem::size_of::<SlabHeader>(), ObjectCache::<T>::slot_align())
+    }
+
+    fn slab_layout(&self) -> Layout {
+        let size = ObjectCache::<T>::objects_offset() + self.slot_size() * MIN_OBJECTS_PER_SLAB;
+        Layout::from_size_align(round_up(size, PAGE_SIZE), cmp::max(PAGE_SIZE, ObjectCache::<T>::slot_align()))
+            .expect("object cache: invalid slab layout")
+    }
+
+    fn objects_per_slab(&self) -> usize {
+        (self.slab_layout().size() - ObjectCache::<T>::objects_offset()) / self.slot_size()
+    }
+
+    /// Take a slab from the heap, construct its objects if the cache has a
+    /// constructor and thread all of them onto its free list
+    unsafe fn new_slab(&self) -> Option<*mut SlabHeader> {
+        let slab = alloc(self.slab_layout()) as *mut SlabHeader;
+        if slab.is_null() {
+            return None;
+        }
+
+        let first = (slab as usize) + ObjectCache::<T>::objects_offset();
+        let mut free = ptr::null_mut();
+        for i in (0..self.objects_per_slab()).rev() {
+            let object = first + i * self.slot_size();
+            if let Some(constructor) = self.constructor {
+                ptr::write(object as *mut T, constructor());
+            }
+            let link = (object + self.link_offset()) as *mut FreeObject;
+            (*link).next = free;
+            free = link;
+        }
+
+        ptr::write(slab, SlabHeader {
+            next: ptr::null_mut(),
+            free: free,
+            in_use: 0,
+        });
+        Some(slab)
+    }
+
+    fn alloc_object(&self) -> Option<(*mut SlabHeader, NonNull<T>)> {
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                if let Some(allocation) = ObjectCache::<T>::take_free_object(&mut inner, self.link_offset()) {
+                    return Some(allocation);
+                }
+            }
+
+            // the lock is not held while allocating, the heap may run a shrinker of this cache
+            let slab = match unsafe { self.new_slab() } {
+                Some(slab) => slab,
+                None => {
+                    self.inner.lock().stats.failed_allocations += 1;
+                    return None;
+                }
+            };
+
+            let mut inner = self.inner.lock();
+            // new slabs go first, they are the most likely to have free objects
+            unsafe { (*slab).next = inner.slabs; }
+            inner.slabs = slab;
+            inner.stats.slabs += 1;
+        }
+    }
+
+    fn take_free_object(inner: &mut CacheInner, link_offset: usize) -> Option<(*mut SlabHeader, NonNull<T>)> {
+        unsafe {
+            let mut slab = inner.slabs;
+            while !slab.is_null() && (*slab).free.is_null() {
+                slab = (*slab).next;
+            }
+            if slab.is_null() {
+                return None;
+            }
+
+            let link = (*slab).free;
+            (*slab).free = (*link).next;
+            (*slab).in_use += 1;
+
+            inner.stats.allocations += 1;
+            inner.stats.objects_in_use += 1;
+            inner.stats.peak_objects_in_use = cmp::max(inner.stats.peak_objects_in_use,
+                                                       inner.stats.objects_in_use);
+            Some((slab, NonNull::new_unchecked((link as usize - link_offset) as *mut T)))
+        }
+    }
+
+    unsafe fn free_object(&self, slab: *mut SlabHeader, object: NonNull<T>) {
+        let mut inner = self.inner.lock();
+        let link = (object.as_ptr() as usize + self.link_offset()) as *mut FreeObject;
+        (*link).next = (*slab).free;
+        (*slab).free = link;
+        (*slab).in_use -= 1;
+
+        inner.stats.frees += 1;
+        inner.stats.objects_in_use -= 1;
+    }
+}
+
+impl<T> Drop for ObjectCache<T> {
+    fn drop(&mut self) {
+        // no objects can be left, they borrow the cache
+        self.shrink();
+    }
+}
+
+/// Shrinker of a registered cache, `cache` is its address
+fn shrink_cache<T>(cache: usize) -> usize {
+    let cache = unsafe { &*(cache as *const ObjectCache<T>) };
+    // shrinkers run inside allocations, so it gives up instead of waiting for the lock
+    let slabs = match cache.inner.try_lock() {
+        Some(mut inner) => cache.take_empty_slabs(&mut inner),
+        None => return 0,
+    };
+    cache.release_slabs(slabs)
+}
+
+fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Owned object from an `ObjectCache`, returned to the cache on drop.
+/// Objects of caches with a constructor are returned without being dropped.
+pub struct CacheBox<'a, T: 'a> {
+    cache: &'a ObjectCache<T>,
+    slab: *mut SlabHeader,
+    object: NonNull<T>,
+}
+
+impl<'a, T> Deref for CacheBox<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<'a, T> DerefMut for CacheBox<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<'a, T> Drop for CacheBox<'a, T> {
+    fn drop(&mut self) {
+        unsafe {
+            if self.cache.constructor.is_none() {
+                ptr::drop_in_place(self.object.as_ptr());
+            }
+            self.cache.free_object(self.slab, self.object);
+        }
+    }
+}
@@ -3,7 +3,12 @@ use multiboot2::ElfSection;
 use multiboot2::{ELF_SECTION_ALLOCATED, ELF_SECTION_WRITABLE, ELF_SECTION_EXECUTABLE};
 
 const ADDRESS_MASK: usize = 0x000f_ffff_ffff_f000;
-const COUNTER_MASK: u64 = 0x3ff00000_00000000;
+/// Bits 52-58 hold the low 7 bits of the table entry counter
+const COUNTER_LOW_MASK: u64 = 0x07f00000_00000000;
+/// Bits 9-11 hold the high 3 bits of the table entry counter.
+/// Bits 59-62 can't be used, they hold the protection key.
+const COUNTER_HIGH_MASK: u64 = 0x00000000_00000e00;
+const COUNTER_MASK: u64 = COUNTER_LOW_MASK | COUNTER_HIGH_MASK;
 
 pub struct Entry(u64);
 
@@ -42,14 +47,20 @@ impl Entry {
         self.0 = (frame.start_address() as u64) | flags.bits() | (self.0 & COUNTER_MASK);
     }
 
-    /// Get bits 52-61 in entry, used as counter for page table
+    /// Replace the flags, keeping the frame and the counter bits
+    pub fn set_flags(&mut self, flags: EntryFlags) {
+        self.0 = (self.0 & (ADDRESS_MASK as u64 | COUNTER_MASK)) | flags.bits();
+    }
+
+    /// Get bits 52-58 and 9-11 in entry, used as counter for page table
     pub fn counter_bits(&self) -> u64 {
-        (self.0 & COUNTER_MASK) >> 52
+        ((self.0 & COUNTER_LOW_MASK) >> 52) | (((self.0 & COUNTER_HIGH_MASK) >> 9) << 7)
     }
 
-    /// Set bits 52-61 in entry, used as counter for page table    
+    /// Set bits 52-58 and 9-11 in entry, used as counter for page table
     pub fn set_counter_bits(&mut self, count: u64) {
-        self.0 = (self.0 & !COUNTER_MASK) | (count << 52);
+        debug_assert!(count < 1 << 10, "counter has only 10 bits");
+        self.0 = (self.0 & !COUNTER_MASK) | ((count & 0x7f) << 52) | ((count >> 7) << 9);
     }
 }
 
@@ -64,11 +75,35 @@ bitflags! {
         const DIRTY =           1 << 6;
         const HUGE_PAGE =       1 << 7;
         const GLOBAL =          1 << 8;
+        const PROTECTION_KEY_0 = 1 << 59;
+        const PROTECTION_KEY_1 = 1 << 60;
+        const PROTECTION_KEY_2 = 1 << 61;
+        const PROTECTION_KEY_3 = 1 << 62;
         const NO_EXECUTE =      1 << 63;
     }
 }
 
+/// Protection key bits, only used by the hardware in leaf entries of user pages
+const PROTECTION_KEY_SHIFT: u64 = 59;
+const PROTECTION_KEY_MASK: u64 = 0xf << PROTECTION_KEY_SHIFT;
+
 impl EntryFlags {
+    /// Flags selecting protection key `key` (0-15)
+    pub fn protection_key(key: u8) -> EntryFlags {
+        debug_assert!(key < 16, "there are only 16 protection keys");
+        EntryFlags::from_bits_truncate((key as u64) << PROTECTION_KEY_SHIFT)
+    }
+
+    /// The protection key selected by these flags
+    pub fn key(&self) -> u8 {
+        ((self.bits() & PROTECTION_KEY_MASK) >> PROTECTION_KEY_SHIFT) as u8
+    }
+
+    /// These flags with the protection key replaced by `key`
+    pub fn with_protection_key(&self, key: u8) -> EntryFlags {
+        EntryFlags::from_bits_truncate(self.bits() & !PROTECTION_KEY_MASK) | EntryFlags::protection_key(key)
+    }
+
     pub fn from_elf_section_flags(section: &ElfSection) -> EntryFlags {
         let mut flags = EntryFlags::empty();
 
@@ -1,10 +1,10 @@
 use core::ptr::Unique;
 use core::mem;
 
-use super::{VirtualAddress, PhysicalAddress, Page, ActivePageTable};
+use super::{VirtualAddress, PhysicalAddress, Page, ActivePageTable, five_level_paging};
 use super::table;
-use super::table::{Table, Level4};
-use super::entry::EntryFlags;
+use super::table::{Table, Level5, Level4};
+use super::entry::{Entry, EntryFlags};
 use memory::{PAGE_SIZE, Frame, allocate_frame, deallocate_frame};
 
 /// In order to enforce correct paging operations in the kernel, these types
@@ -77,22 +77,56 @@ impl Drop for MapperFlushAll {
 }
 
 pub struct Mapper {
-    p4: Unique<Table<Level4>>,
+    /// Top level table. With five-level paging this is the P5 table,
+    /// the P4 tables are then reached through its entries.
+    root: Unique<Table<Level4>>,
 }
 
 impl Mapper {
     pub unsafe fn new() -> Mapper {
         Mapper {
-            p4: Unique::new_unchecked(table::P4),
+            root: Unique::new_unchecked(table::P4),
         }
     }
 
-    pub fn p4(&self) -> &Table<Level4> {
-        unsafe { self.p4.as_ref() }
+    /// The top level table, only meant for accessing the recursive entry
+    pub fn root_table_mut(&mut self) -> &mut Table<Level4> {
+        unsafe { self.root.as_mut() }
     }
 
-    pub fn p4_mut(&mut self) -> &mut Table<Level4> {
-        unsafe { self.p4.as_mut() }
+    fn p5(&self) -> &Table<Level5> {
+        debug_assert!(five_level_paging());
+        unsafe { &*table::P5 }
+    }
+
+    fn p5_mut(&mut self) -> &mut Table<Level5> {
+        debug_assert!(five_level_paging());
+        unsafe { &mut *table::P5 }
+    }
+
+    /// The P4 table responsible for `page`, if present
+    pub fn p4(&self, page: Page) -> Option<&Table<Level4>> {
+        if five_level_paging() {
+            self.p5().next_table(page.p5_index())
+        } else {
+            Some(unsafe { self.root.as_ref() })
+        }
+    }
+
+    pub fn p4_mut(&mut self, page: Page) -> Option<&mut Table<Level4>> {
+        if five_level_paging() {
+            self.p5_mut().next_table_mut(page.p5_index())
+        } else {
+            Some(unsafe { self.root.as_mut() })
+        }
+    }
+
+    fn p4_create(&mut self, page: Page) -> &mut Table<Level4> {
+        if five_level_paging() {
+            self.p5_mut().next_table_create(page.p5_index())
+        } else {
+            unsafe { self.root.as_mut() }
+        }
     }
 
     pub fn translate(&self, virtual_address: VirtualAddress) -> Option<PhysicalAddress> {
@@ -102,14 +136,67 @@ impl Mapper {
     }
 
     pub fn translate_page(&self, page: Page) -> Option<Frame> {
-        self.p4().next_table(page.p4_index())
+        self.p4(page)
+        .and_then(|p4| p4.next_table(page.p4_index()))
         .and_then(|p3| p3.next_table(page.p3_index()))
         .and_then(|p2| p2.next_table(page.p2_index()))
         .and_then(|p1| p1[page.p1_index()].pointed_frame())
     }
 
+    fn p1_entry_mut(&mut self, page: Page) -> Option<&mut Entry> {
+        self.p4_mut(page)
+        .and_then(|p4| p4.next_table_mut(page.p4_index()))
+        .and_then(|p3| p3.next_table_mut(page.p3_index()))
+        .and_then(|p2| p2.next_table_mut(page.p2_index()))
+        .map(|p1| &mut p1[page.p1_index()])
+    }
+
+    /// Get the flags of a mapped page
+    pub fn flags(&self, page: Page) -> Option<EntryFlags> {
+        self.p4(page)
+        .and_then(|p4| p4.next_table(page.p4_index()))
+        .and_then(|p3| p3.next_table(page.p3_index()))
+        .and_then(|p2| p2.next_table(page.p2_index()))
+        .map(|p1| p1[page.p1_index()].flags())
+        .filter(|flags| flags.contains(EntryFlags::PRESENT))
+    }
+
+    /// Replace the flags of a mapped page
+    pub fn set_flags(&mut self, page: Page, flags: EntryFlags) -> MapperFlush {
+        {
+            let entry = self.p1_entry_mut(page).expect("set_flags: page not mapped");
+            assert!(entry.flags().contains(EntryFlags::PRESENT), "set_flags: page not mapped");
+            entry.set_flags(flags | EntryFlags::PRESENT);
+        }
+        MapperFlush::new(page)
+    }
+
+    /// Set USER_ACCESSIBLE in the table entries above `page`, so that the
+    /// leaf entry alone decides whether `page` is a user page
+    pub fn set_tables_user_accessible(&mut self, page: Page) -> MapperFlush {
+        if five_level_paging() {
+            let entry = &mut self.p5_mut()[page.p5_index()];
+            let flags = entry.flags();
+            entry.set_flags(flags | EntryFlags::USER_ACCESSIBLE);
+        }
+        {
+            let p4 = self.p4_mut(page).expect("set_tables_user_accessible: page not mapped");
+            let flags = p4[page.p4_index()].flags();
+            p4[page.p4_index()].set_flags(flags | EntryFlags::USER_ACCESSIBLE);
+
+            let p3 = p4.next_table_mut(page.p4_index()).expect("set_tables_user_accessible: page not mapped");
+            let flags = p3[page.p3_index()].flags();
+            p3[page.p3_index()].set_flags(flags | EntryFlags::USER_ACCESSIBLE);
+
+            let p2 = p3.next_table_mut(page.p3_index()).expect("set_tables_user_accessible: page not mapped");
+            let flags = p2[page.p2_index()].flags();
+            p2[page.p2_index()].set_flags(flags | EntryFlags::USER_ACCESSIBLE);
+        }
+        MapperFlush::new(page)
+    }
+
     pub fn map_to(&mut self, page: Page, frame: Frame, flags: EntryFlags) -> MapperFlush {   
-        let p3 = self.p4_mut().next_table_create(page.p4_index());
+        let p3 = self.p4_create(page).next_table_create(page.p4_index());
         let p2 = p3.next_table_create(page.p3_index());
         let p1 = p2.next_table_create(page.p2_index());
 
@@ -133,9 +220,40 @@ impl Mapper {
 
     pub fn unmap_inner(&mut self, page: &Page, keep_parents: bool) -> Frame {
         assert!(self.translate(page.start_address()).is_some());
+
+        if !five_level_paging() {
+            let (frame, _) = Mapper::unmap_in_p4(self.root_table_mut(), page, keep_parents);
+            return frame;
+        }
+
+        let (frame, p4_unused) = {
+            let p4 = self.p4_mut(*page).unwrap_or_else(|| {
+                panic!("unmap_inner({:X}): p4 not found", page.start_address())
+            });
+            Mapper::unmap_in_p4(p4, page, keep_parents)
+        };
+
+        if keep_parents || ! p4_unused {
+            return frame;
+        }
+
+        let p5 = self.p5_mut();
+        if let Some(p4_frame) = p5[page.p5_index()].pointed_frame() {
+            p5.decrement_entry_count();
+            p5[page.p5_index()].set_unused();
+            deallocate_frame(p4_frame);
+        } else {
+            panic!("unmap_inner({:X}): p4_frame not found", page.start_address());
+        }
+
+        frame
+    }
+
+    /// Unmap a page from the tables below `p4`, freeing tables that became empty
+    /// unless `keep_parents` is set. Also returns whether `p4` itself became empty.
+    fn unmap_in_p4(p4: &mut Table<Level4>, page: &Page, keep_parents: bool) -> (Frame, bool) {
         let frame;
 
-        let p4 = self.p4_mut();
         if let Some(p3) = p4.next_table_mut(page.p4_index()) {
             if let Some(p2) = p3.next_table_mut(page.p3_index()) {
                 if let Some(p1) = p2.next_table_mut(page.p2_index()) {
@@ -149,7 +267,7 @@ impl Mapper {
                     p1[page.p1_index()].set_unused();
 
                     if keep_parents || ! p1.is_unused() {
-                        return frame;
+                        return (frame, false);
                     }
                 } else {
                     panic!("unmap_inner({:X}): p1 not found", page.start_address());
@@ -165,7 +283,7 @@ impl Mapper {
                 }
 
                 if ! p2.is_unused() {
-                    return frame;
+                    return (frame, false);
                 }
             } else {
                 panic!("unmap_inner({:X}): p2 not found", page.start_address());
@@ -181,7 +299,7 @@ impl Mapper {
             }
 
             if ! p3.is_unused() {
-                return frame;
+                return (frame, false);
             }
         } else {
             panic!("unmap_inner({:X}): p3 not found", page.start_address());
@@ -196,7 +314,7 @@ impl Mapper {
             panic!("unmap_inner({:X}): p3_frame not found", page.start_address());
         }
 
-        frame
+        (frame, p4.is_unused())
     }
 
     /// Unmap a page
@@ -14,8 +14,12 @@ use x86_64::instructions::tlb;
 use x86_64::registers::control::{Cr3, Cr3Flags};
 use x86_64::structures::paging::PhysFrame;
 
-use self::mapper::Mapper;
+pub use self::mapper::{Mapper, MapperFlush};
 use core::ops::{Deref, DerefMut, Add};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cpu::{self, Cr4Flags};
+use sync::IrqMutex;
 
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
@@ -23,7 +27,63 @@ pub type VirtualAddress = usize;
 pub const PAGE_SIZE: usize = 4096;
 const ENTRY_COUNT: usize = 512;
 
-use self::temporary_page::TemporaryPage;
+pub use self::temporary_page::TemporaryPage;
+
+/// Latched from CR4 by `detect_five_level_paging`, reading CR4 is privileged and serializing
+static FIVE_LEVEL_PAGING: AtomicBool = AtomicBool::new(false);
+
+/// Record whether the boot code enabled five-level paging (LA57).
+/// The mode can't be changed while in long mode, so it is read once at boot,
+/// before the first address is converted to a page.
+pub fn detect_five_level_paging() {
+    FIVE_LEVEL_PAGING.store(cpu::read_cr4().contains(Cr4Flags::LA57), Ordering::Relaxed);
+}
+
+/// Whether the boot code enabled five-level paging, four-level paging is
+/// assumed until `detect_five_level_paging` ran
+pub fn five_level_paging() -> bool {
+    FIVE_LEVEL_PAGING.load(Ordering::Relaxed)
+}
+
+/// The only handle to the active table once the kernel is remapped. The
+/// memory controller, the heap, vmalloc, the stack allocator, the physical
+/// map and the shrinkers all change mappings through it.
+/// Code holding it must not allocate from the heap: the heap maps its pages
+/// through this lock too and only tries to take it, so an allocation that
+/// needs a new heap page fails instead of deadlocking. Freeing is fine,
+/// the heap unmaps the pages later if the table is busy.
+static ACTIVE_TABLE: IrqMutex<Option<ActivePageTable>> = IrqMutex::new(None);
+
+/// Run `f` with the active table locked
+pub fn with_active_table<F, R>(f: F) -> R
+    where F: FnOnce(&mut ActivePageTable) -> R
+{
+    if let Some(ref mut active_table) = *ACTIVE_TABLE.lock() {
+        f(active_table)
+    } else {
+        panic!("active page table not initialized");
+    }
+}
+
+/// Like `with_active_table`, but returns None instead of waiting if the
+/// table is locked. For the heap and the shrinkers, which run inside
+/// allocations that may have been made with the table locked.
+pub fn try_with_active_table<F, R>(f: F) -> Option<R>
+    where F: FnOnce(&mut ActivePageTable) -> R
+{
+    let mut active_table = match ACTIVE_TABLE.try_lock() {
+        Some(active_table) => active_table,
+        None => return None,
+    };
+    let result = match *active_table {
+        Some(ref mut active_table) => f(active_table),
+        None => panic!("active page table not initialized"),
+    };
+    Some(result)
+}
+
+/// Page used to temporarily map frames that are not part of the active table
+pub const TEMPORARY_PAGE: Page = Page { number: 0xcafebabe };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
@@ -39,16 +99,30 @@ impl Page {
     }
 
     pub fn containing_address(address: VirtualAddress) -> Page {
-        assert!(address < 0x0000_8000_0000_0000 ||
-                address >= 0xffff_8000_0000_0000,
-                "invalid address: 0x{:x}", address);
+        if five_level_paging() {
+            assert!(address < 0x0100_0000_0000_0000 ||
+                    address >= 0xff00_0000_0000_0000,
+                    "invalid address: 0x{:x}", address);
+        } else {
+            assert!(address < 0x0000_8000_0000_0000 ||
+                    address >= 0xffff_8000_0000_0000,
+                    "invalid address: 0x{:x}", address);
+        }
         Page { number: address / PAGE_SIZE }
     }
 
+    /// The page with number `number`, i.e. at address `number * PAGE_SIZE`
+    pub fn from_number(number: usize) -> Page {
+        Page { number: number }
+    }
+
     pub fn start_address(&self) -> usize {
         self.number * PAGE_SIZE
     }
 
+    fn p5_index(&self) -> usize {
+        (self.number >> 36) & 0o777
+    }
     fn p4_index(&self) -> usize {
         (self.number >> 27) & 0o777
     }
@@ -110,6 +184,9 @@ impl DerefMut for ActivePageTable {
 }
 
 impl ActivePageTable {
+    /// Access the active table through the recursive mapping.
+    /// Unsafe because the caller has to make sure no one else modifies the same entries,
+    /// only `remap_the_kernel` creates one, it ends up in `ACTIVE_TABLE`.
     unsafe fn new() -> ActivePageTable {
         ActivePageTable {
             mapper: Mapper::new(),
@@ -129,7 +206,7 @@ impl ActivePageTable {
             let p4_table = temporary_page.map_table_frame(backup.clone(), self);
 
             // overwrite recursive mapping
-            self.p4_mut()[511].set(table.p4_frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
+            self.root_table_mut()[511].set(table.p4_frame.clone(), EntryFlags::PRESENT | EntryFlags::WRITABLE);
             self.flush_all();
 
             // execute f in the new context
@@ -167,6 +244,7 @@ impl ActivePageTable {
 }
 
 pub struct InactivePageTable {
+    /// Frame of the top level table, the P5 table with five-level paging
     p4_frame: Frame,
 }
 
@@ -189,8 +267,10 @@ impl InactivePageTable {
 
 }
 
-pub fn remap_the_kernel(boot_info: &BootInformation) -> ActivePageTable {
-    let mut temporary_page = TemporaryPage::new(Page { number: 0xcafebabe });
+/// Switch to a new table with the kernel sections mapped with the right
+/// flags, it can then be used through `with_active_table`
+pub fn remap_the_kernel(boot_info: &BootInformation) {
+    let mut temporary_page = TemporaryPage::new(TEMPORARY_PAGE);
 
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
@@ -246,5 +326,5 @@ pub fn remap_the_kernel(boot_info: &BootInformation) -> ActivePageTable {
     let result = active_table.unmap(old_p4_page);
     result.flush(&mut active_table);
     println!("guard page at {:#x}", old_p4_page.start_address());
-    active_table
+    *ACTIVE_TABLE.lock() = Some(active_table);
 }
@@ -6,7 +6,10 @@ use memory::paging::entry::{Entry, EntryFlags};
 
 use memory::allocate_frame;
 
+/// Top level table, reachable through the recursive mapping in its last entry.
+/// With five-level paging the P5 table lives at this address instead.
 pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
+pub const P5: *mut Table<Level5> = 0xffffffff_fffff000 as *mut _;
 
 pub struct Table<L: TableLevel> {
     entries: [Entry; ENTRY_COUNT],
@@ -102,11 +105,13 @@ impl<L> IndexMut<usize> for Table<L> where L: TableLevel {
 
 pub trait TableLevel {}
 
+pub enum Level5 {}
 pub enum Level4 {}
 pub enum Level3 {}
 pub enum Level2 {}
 pub enum Level1 {}
 
+impl TableLevel for Level5 {}
 impl TableLevel for Level4 {}
 impl TableLevel for Level3 {}
 impl TableLevel for Level2 {}
@@ -116,6 +121,9 @@ pub trait HierarchicalLevel: TableLevel {
     type NextLevel: TableLevel;
 }
 
+impl HierarchicalLevel for Level5 {
+    type NextLevel = Level4;
+}
 impl HierarchicalLevel for Level4 {
     type NextLevel = Level3;
 }
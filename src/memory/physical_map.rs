@@ -0,0 +1,42 @@
+//! Access to physical memory that is not handed out by the frame allocator,
+//! like firmware tables and device registers. Physical address `p` is mapped
+//! at `PHYSICAL_MAP_START + p`, pages are mapped the first time they are
+//! requested and stay mapped. The frames are never freed.
+
+use memory::Frame;
+use memory::paging::{self, Page, PhysicalAddress, VirtualAddress, EntryFlags};
+
+pub const PHYSICAL_MAP_START: usize = 0o_001_000_000_000_0000;
+/// Physical memory that can be reached through the window
+pub const PHYSICAL_MAP_SIZE: usize = 512 * 1024 * 1024 * 1024; // 512 GiB
+
+/// Map `size` bytes of physical memory starting at `address` and return the
+/// virtual address of `address`. Pages already mapped keep their flags.
+pub fn map_physical(address: PhysicalAddress, size: usize, flags: EntryFlags) -> VirtualAddress {
+    assert!(size > 0 && address + size <= PHYSICAL_MAP_SIZE,
+            "physical map: {:#x} (size {:#x}) out of range", address, size);
+
+    let first = Frame::containing_address(address);
+    let last = Frame::containing_address(address + size - 1);
+    paging::with_active_table(|active_table| {
+        for frame in Frame::range_inclusive(first, last) {
+            let page = Page::containing_address(PHYSICAL_MAP_START + frame.start_address());
+            if active_table.translate_page(page).is_none() {
+                let result = active_table.map_to(page, frame, flags);
+                result.flush(active_table);
+            }
+        }
+    });
+
+    PHYSICAL_MAP_START + address
+}
+
+/// Map firmware tables, read-only
+pub fn map_table(address: PhysicalAddress, size: usize) -> VirtualAddress {
+    map_physical(address, size, EntryFlags::NO_EXECUTE)
+}
+
+/// Map device registers, uncached
+pub fn map_mmio(address: PhysicalAddress, size: usize) -> VirtualAddress {
+    map_physical(address, size, EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::NO_EXECUTE)
+}
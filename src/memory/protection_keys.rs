@@ -0,0 +1,150 @@
+//! Protection keys (PKU). The hardware only checks keys of user pages, so
+//! protected pages are mapped USER_ACCESSIBLE. There is no user mode yet,
+//! the bit only makes the PKRU register apply to them.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use cpu::{self, Cr4Flags};
+use memory::paging::{ActivePageTable, PageIter, EntryFlags};
+
+/// Key 0 is used by every page that was not assigned another key
+const DEFAULT_KEY: u8 = 0;
+const KEY_COUNT: u8 = 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Bit `n` is set if key `n` is in use
+static ALLOCATED_KEYS: Mutex<u16> = Mutex::new(1 << DEFAULT_KEY);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    None,
+    ReadOnly,
+    ReadWrite,
+}
+
+impl Access {
+    /// Access-disable and write-disable bits of a key in PKRU
+    fn pkru_bits(&self) -> u32 {
+        match *self {
+            Access::None => 0b01,
+            Access::ReadOnly => 0b10,
+            Access::ReadWrite => 0b00,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProtectionKey(u8);
+
+impl ProtectionKey {
+    pub fn index(&self) -> u8 {
+        self.0
+    }
+}
+
+fn read_pkru() -> u32 {
+    let value: u32;
+    // rdpkru
+    unsafe { asm!(".byte 0x0f, 0x01, 0xee" : "={eax}"(value) : "{ecx}"(0) : "edx" : "volatile"); }
+    value
+}
+
+unsafe fn write_pkru(value: u32) {
+    // wrpkru
+    asm!(".byte 0x0f, 0x01, 0xef" :: "{eax}"(value), "{ecx}"(0), "{edx}"(0) : "memory" : "volatile");
+}
+
+fn set_pkru_access(pkru: u32, key: u8, access: Access) -> u32 {
+    let shift = key as u32 * 2;
+    (pkru & !(0b11 << shift)) | (access.pkru_bits() << shift)
+}
+
+/// Enable protection keys if the CPU supports them. Returns whether they are enabled.
+pub fn init() -> bool {
+    if !cpu::has_pku() {
+        return false;
+    }
+
+    unsafe {
+        let mut flags = cpu::read_cr4();
+        flags.insert(Cr4Flags::PROTECTION_KEY);
+        cpu::write_cr4(flags);
+        // every key allows full access until it gets allocated
+        write_pkru(0);
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+    true
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Allocate a key. Pages protected with it are inaccessible by default.
+pub fn allocate() -> Option<ProtectionKey> {
+    if !enabled() {
+        return None;
+    }
+
+    let mut allocated = ALLOCATED_KEYS.lock();
+    for key in 0..KEY_COUNT {
+        if *allocated & (1 << key) == 0 {
+            *allocated |= 1 << key;
+            set_access(&ProtectionKey(key), Access::None);
+            return Some(ProtectionKey(key));
+        }
+    }
+    None
+}
+
+/// Free a key. Pages still using it must be switched back to the default key first.
+pub fn free(key: ProtectionKey) {
+    set_access(&key, Access::ReadWrite);
+    *ALLOCATED_KEYS.lock() &= !(1 << key.0);
+}
+
+/// Access allowed through `key` outside of `with_access` windows
+pub fn set_access(key: &ProtectionKey, access: Access) {
+    unsafe { write_pkru(set_pkru_access(read_pkru(), key.0, access)); }
+}
+
+/// Run `f` with `access` allowed through `key`, restoring the previous rights afterwards
+pub fn with_access<F, R>(key: &ProtectionKey, access: Access, f: F) -> R
+    where F: FnOnce() -> R
+{
+    let saved = read_pkru();
+    unsafe { write_pkru(set_pkru_access(saved, key.0, access)); }
+    let result = f();
+    unsafe { write_pkru(saved); }
+    result
+}
+
+/// Assign `key` to all pages in `pages`, which have to be mapped
+pub fn protect(active_table: &mut ActivePageTable, pages: PageIter, key: &ProtectionKey) {
+    assign(active_table, pages, key.0);
+}
+
+/// Switch all pages in `pages` back to the default key
+pub fn unprotect(active_table: &mut ActivePageTable, pages: PageIter) {
+    assign(active_table, pages, DEFAULT_KEY);
+}
+
+fn assign(active_table: &mut ActivePageTable, pages: PageIter, key: u8) {
+    for page in pages {
+        let flags = active_table.flags(page).expect("protection keys: page not mapped");
+        let flags = if key == DEFAULT_KEY {
+            flags - EntryFlags::USER_ACCESSIBLE
+        } else {
+            flags | EntryFlags::USER_ACCESSIBLE
+        };
+
+        let result = active_table.set_tables_user_accessible(page);
+        // flushed together with the leaf entry below
+        unsafe { result.ignore(); }
+        let result = active_table.set_flags(page, flags.with_protection_key(key));
+        result.flush(active_table);
+    }
+}
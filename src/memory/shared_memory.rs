@@ -0,0 +1,169 @@
+use core::mem;
+use core::ptr;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use memory::{Frame, allocate_frame, deallocate_frame};
+use memory::paging::{self, Page, PageIter, ActivePageTable, InactivePageTable, TemporaryPage, EntryFlags, PAGE_SIZE};
+
+/// Frames backing a shared memory object. They are returned to the frame
+/// allocator when the last handle and the last mapping are gone.
+struct SharedFrames {
+    frames: Vec<Frame>,
+}
+
+impl Drop for SharedFrames {
+    fn drop(&mut self) {
+        for frame in self.frames.drain(..) {
+            deallocate_frame(frame);
+        }
+    }
+}
+
+/// Handle to a refcounted set of frames that can be mapped into several
+/// page tables at different virtual addresses with different permissions
+#[derive(Clone)]
+pub struct SharedMemory {
+    frames: Arc<SharedFrames>,
+}
+
+impl SharedMemory {
+    /// Allocate `size_in_pages` zeroed frames.
+    /// Returns None if there are not enough free frames.
+    pub fn new(size_in_pages: usize, temporary_page: &mut TemporaryPage) -> Option<SharedMemory> {
+        if size_in_pages == 0 {
+            return None;
+        }
+
+        let mut frames = Vec::with_capacity(size_in_pages);
+        for _ in 0..size_in_pages {
+            match allocate_frame() {
+                Some(frame) => frames.push(frame),
+                None => {
+                    for frame in frames.drain(..) {
+                        deallocate_frame(frame);
+                    }
+                    return None;
+                }
+            }
+        }
+
+        // the frames may contain data of their previous owner
+        paging::with_active_table(|active_table| {
+            for frame in frames.iter() {
+                let address = temporary_page.map(frame.clone(), active_table);
+                unsafe { ptr::write_bytes(address as *mut u8, 0, PAGE_SIZE); }
+                temporary_page.unmap(active_table);
+            }
+        });
+
+        Some(SharedMemory {
+            frames: Arc::new(SharedFrames { frames: frames }),
+        })
+    }
+
+    pub fn size_in_pages(&self) -> usize {
+        self.frames.frames.len()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size_in_pages() * PAGE_SIZE
+    }
+
+    /// Number of handles and mappings currently referencing the frames
+    pub fn reference_count(&self) -> usize {
+        Arc::strong_count(&self.frames)
+    }
+
+    /// Map the object into the active table starting at `start`
+    pub fn map(&self, active_table: &mut ActivePageTable, start: Page, flags: EntryFlags) -> SharedMapping {
+        for (page, frame) in self.pages(start) {
+            let result = active_table.map_to(page, frame, flags);
+            result.flush(active_table);
+        }
+        SharedMapping::new(self.frames.clone(), start)
+    }
+
+    /// Map the object into an inactive table starting at `start`
+    pub fn map_inactive(&self, active_table: &mut ActivePageTable, table: &mut InactivePageTable,
+                        temporary_page: &mut TemporaryPage, start: Page, flags: EntryFlags) -> SharedMapping {
+        active_table.with(table, temporary_page, |mapper| {
+            for (page, frame) in self.pages(start) {
+                let result = mapper.map_to(page, frame, flags);
+                // The flush can be ignored as this is not the active table
+                unsafe { result.ignore(); }
+            }
+        });
+        SharedMapping::new(self.frames.clone(), start)
+    }
+
+    fn pages<'a>(&'a self, start: Page) -> impl Iterator<Item = (Page, Frame)> + 'a {
+        self.frames.frames.iter().enumerate().map(move |(i, frame)| (start + i, frame.clone()))
+    }
+}
+
+/// A shared memory object mapped at some virtual address.
+/// Keeps the frames alive until it is explicitly unmapped.
+#[must_use = "A shared mapping must be unmapped"]
+pub struct SharedMapping {
+    frames: Option<Arc<SharedFrames>>,
+    start: Page,
+}
+
+impl SharedMapping {
+    fn new(frames: Arc<SharedFrames>, start: Page) -> SharedMapping {
+        SharedMapping {
+            frames: Some(frames),
+            start: start,
+        }
+    }
+
+    pub fn start_address(&self) -> usize {
+        self.start.start_address()
+    }
+
+    pub fn size_in_pages(&self) -> usize {
+        self.frames.as_ref().map_or(0, |frames| frames.frames.len())
+    }
+
+    /// Unmap from the active table
+    pub fn unmap(self, active_table: &mut ActivePageTable) {
+        for page in self.pages() {
+            let (result, _frame) = active_table.unmap_return(page, false);
+            result.flush(active_table);
+        }
+        self.release();
+    }
+
+    /// Unmap from an inactive table
+    pub fn unmap_inactive(self, active_table: &mut ActivePageTable, table: &mut InactivePageTable,
+                          temporary_page: &mut TemporaryPage) {
+        let pages = self.pages();
+        active_table.with(table, temporary_page, |mapper| {
+            for page in pages {
+                let (result, _frame) = mapper.unmap_return(page, false);
+                // The flush can be ignored as this is not the active table
+                unsafe { result.ignore(); }
+            }
+        });
+        self.release();
+    }
+
+    fn pages(&self) -> PageIter {
+        Page::range_inclusive(self.start, self.start + (self.size_in_pages() - 1))
+    }
+
+    /// Drop the reference to the frames, freeing them if this was the last one
+    fn release(mut self) {
+        self.frames.take();
+        mem::forget(self);
+    }
+}
+
+/// A mapping cannot be dropped, it must be unmapped
+impl Drop for SharedMapping {
+    fn drop(&mut self) {
+        panic!("Shared mapping was not unmapped");
+    }
+}
@@ -0,0 +1,82 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use sync::IrqMutex;
+
+/// Frees memory held by a cache. Returns the number of bytes released.
+/// Called when an allocation fails, so it must not rely on allocating itself.
+pub type Shrinker = fn() -> usize;
+
+const MAX_SHRINKERS: usize = 16;
+
+#[derive(Clone, Copy)]
+enum Callback {
+    Plain(Shrinker),
+    /// Called with an argument, like the address of the cache it shrinks
+    WithArgument(fn(usize) -> usize, usize),
+}
+
+impl Callback {
+    fn call(self) -> usize {
+        match self {
+            Callback::Plain(shrinker) => shrinker(),
+            Callback::WithArgument(shrinker, argument) => shrinker(argument),
+        }
+    }
+}
+
+/// Taken by every allocation that fails, including those made from interrupt handlers
+static SHRINKERS: IrqMutex<[Option<(&'static str, Callback)>; MAX_SHRINKERS]> = IrqMutex::new([None; MAX_SHRINKERS]);
+
+/// Set while the shrinkers run, so a failing allocation inside one doesn't start them again
+static SHRINKING: AtomicBool = AtomicBool::new(false);
+
+fn register(name: &'static str, callback: Callback) -> bool {
+    let mut shrinkers = SHRINKERS.lock();
+    for slot in shrinkers.iter_mut() {
+        if slot.is_none() {
+            *slot = Some((name, callback));
+            return true;
+        }
+    }
+    false
+}
+
+/// Register a shrinker, returns false if there is no free slot
+pub fn register_shrinker(name: &'static str, shrinker: Shrinker) -> bool {
+    register(name, Callback::Plain(shrinker))
+}
+
+/// Register a shrinker that is called with `argument`,
+/// returns false if there is no free slot
+pub fn register_shrinker_with(name: &'static str, shrinker: fn(usize) -> usize, argument: usize) -> bool {
+    register(name, Callback::WithArgument(shrinker, argument))
+}
+
+pub fn unregister_shrinker(shrinker: Shrinker) {
+    let mut shrinkers = SHRINKERS.lock();
+    for slot in shrinkers.iter_mut() {
+        if let Some((_, Callback::Plain(registered))) = *slot {
+            if registered as usize == shrinker as usize {
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// Ask every registered shrinker to release memory.
+/// Returns the total number of bytes released.
+pub fn shrink_all() -> usize {
+    if SHRINKING.swap(true, Ordering::SeqCst) {
+        return 0;
+    }
+
+    // the lock is not held while shrinkers run, they may free memory
+    let shrinkers = *SHRINKERS.lock();
+    let mut released = 0;
+    for &(_, callback) in shrinkers.iter().filter_map(|slot| slot.as_ref()) {
+        released += callback.call();
+    }
+
+    SHRINKING.store(false, Ordering::SeqCst);
+    released
+}
@@ -0,0 +1,285 @@
+use core::u16;
+use core::ptr::NonNull;
+
+use memory::paging::{Page, VirtualAddress, PAGE_SIZE};
+
+/// Object sizes from 64 B to 4 KiB
+pub const SLAB_CLASSES: usize = 7;
+
+/// End of a list of pages or of the free objects of a page
+const NONE: u16 = u16::MAX;
+
+/// Bookkeeping for one page of the slab area. The free objects of the page
+/// form a list, each holds the offset of the next one in its first bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct SlabPage {
+    next: u16,
+    prev: u16,
+    /// Offset of the first free object in the page
+    free: u16,
+    in_use: u16,
+    class: u8,
+}
+
+pub const UNUSED_SLAB_PAGE: SlabPage = SlabPage {
+    next: NONE,
+    prev: NONE,
+    free: NONE,
+    in_use: 0,
+    class: 0,
+};
+
+pub fn object_size(class: usize) -> usize {
+    64 << class
+}
+
+fn objects_per_page(class: usize) -> usize {
+    PAGE_SIZE / object_size(class)
+}
+
+/// Slabs of a single page each, so a page whose objects are all free can be
+/// unmapped and its frame returned. Only the objects are managed, mapping the
+/// pages is up to the caller: it adds mapped pages with `add_page` and takes
+/// the empty ones back with `take_empty_page`.
+///
+/// A page is on the partial list of its class while some of its objects are
+/// free and some are in use, on the empty list once all are free, and on no
+/// list while all are in use.
+pub struct Slabs<'a> {
+    start: VirtualAddress,
+    pages: &'a mut [SlabPage],
+    partial: [u16; SLAB_CLASSES],
+    empty: [u16; SLAB_CLASSES],
+    empty_pages: [usize; SLAB_CLASSES],
+}
+
+impl<'a> Slabs<'a> {
+    /// Manage the objects of the pages starting at `start`, one entry of
+    /// `pages` per page
+    pub fn new(start: Page, pages: &'a mut [SlabPage]) -> Slabs<'a> {
+        assert!(pages.len() < NONE as usize, "Slab area has too many pages");
+        Slabs {
+            start: start.start_address(),
+            pages: pages,
+            partial: [NONE; SLAB_CLASSES],
+            empty: [NONE; SLAB_CLASSES],
+            empty_pages: [0; SLAB_CLASSES],
+        }
+    }
+
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.start && address < self.start + self.pages.len() * PAGE_SIZE
+    }
+
+    fn page_address(&self, index: u16) -> VirtualAddress {
+        self.start + index as usize * PAGE_SIZE
+    }
+
+    /// Take a free object of `class`, preferring pages already in use.
+    /// Returns None if the class needs another page.
+    pub fn allocate(&mut self, class: usize) -> Option<NonNull<u8>> {
+        let index = if self.partial[class] != NONE {
+            self.partial[class]
+        } else if self.empty[class] != NONE {
+            let index = self.empty[class];
+            unlink(self.pages, &mut self.empty[class], index);
+            self.empty_pages[class] -= 1;
+            push(self.pages, &mut self.partial[class], index);
+            index
+        } else {
+            return None;
+        };
+
+        let page_address = self.page_address(index);
+        let (address, full) = {
+            let page = &mut self.pages[index as usize];
+            let address = page_address + page.free as usize;
+            page.free = unsafe { *(address as *const u16) };
+            page.in_use += 1;
+            (address, page.free == NONE)
+        };
+        if full {
+            unlink(self.pages, &mut self.partial[class], index);
+        }
+        NonNull::new(address as *mut u8)
+    }
+
+    /// Give a mapped, unused page to `class`
+    pub unsafe fn add_page(&mut self, page: Page, class: usize) {
+        let index = self.index_of(page.start_address());
+        let size = object_size(class);
+        let count = objects_per_page(class);
+        let start = self.page_address(index);
+        for i in 0..count {
+            let next = if i + 1 < count { ((i + 1) * size) as u16 } else { NONE };
+            *((start + i * size) as *mut u16) = next;
+        }
+
+        self.pages[index as usize] = SlabPage {
+            free: 0,
+            in_use: 0,
+            class: class as u8,
+            ..UNUSED_SLAB_PAGE
+        };
+        push(self.pages, &mut self.empty[class], index);
+        self.empty_pages[class] += 1;
+    }
+
+    /// Return an object of `class` returned by `allocate`
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, class: usize) {
+        let address = ptr.as_ptr() as usize;
+        let index = self.index_of(address);
+        let offset = (address - self.start) % PAGE_SIZE;
+
+        let (was_full, in_use) = {
+            let page = &mut self.pages[index as usize];
+            debug_assert!(page.class as usize == class && page.in_use > 0,
+                          "slab: {:#x} was not allocated from this class", address);
+            *(address as *mut u16) = page.free;
+            page.free = offset as u16;
+            let was_full = page.in_use as usize == objects_per_page(class);
+            page.in_use -= 1;
+            (was_full, page.in_use)
+        };
+
+        if in_use == 0 {
+            if !was_full {
+                unlink(self.pages, &mut self.partial[class], index);
+            }
+            push(self.pages, &mut self.empty[class], index);
+            self.empty_pages[class] += 1;
+        } else if was_full {
+            push(self.pages, &mut self.partial[class], index);
+        }
+    }
+
+    /// Pages whose objects are all free, over all classes
+    pub fn empty_pages(&self) -> usize {
+        self.empty_pages.iter().sum()
+    }
+
+    /// Whether some class has more than `keep` empty pages
+    pub fn has_empty_pages(&self, keep: usize) -> bool {
+        self.empty_pages.iter().any(|&count| count > keep)
+    }
+
+    /// Take an empty page away from a class that has more than `keep` of
+    /// them. The caller unmaps it.
+    pub fn take_empty_page(&mut self, keep: usize) -> Option<Page> {
+        let class = match (0..SLAB_CLASSES).find(|&class| self.empty_pages[class] > keep) {
+            Some(class) => class,
+            None => return None,
+        };
+        let index = self.empty[class];
+        unlink(self.pages, &mut self.empty[class], index);
+        self.empty_pages[class] -= 1;
+        self.pages[index as usize] = UNUSED_SLAB_PAGE;
+        Some(Page::containing_address(self.page_address(index)))
+    }
+
+    fn index_of(&self, address: VirtualAddress) -> u16 {
+        debug_assert!(self.contains(address));
+        ((address - self.start) / PAGE_SIZE) as u16
+    }
+}
+
+/// Insert page `index` at the front of the list starting at `head`
+fn push(pages: &mut [SlabPage], head: &mut u16, index: u16) {
+    pages[index as usize].prev = NONE;
+    pages[index as usize].next = *head;
+    if *head != NONE {
+        pages[*head as usize].prev = index;
+    }
+    *head = index;
+}
+
+/// Remove page `index` from the list starting at `head`
+fn unlink(pages: &mut [SlabPage], head: &mut u16, index: u16) {
+    let SlabPage { next, prev, .. } = pages[index as usize];
+    if prev == NONE {
+        *head = next;
+    } else {
+        pages[prev as usize].next = next;
+    }
+    if next != NONE {
+        pages[next as usize].prev = prev;
+    }
+    pages[index as usize].next = NONE;
+    pages[index as usize].prev = NONE;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::alloc::Layout;
+    use std::alloc::{alloc, dealloc};
+
+    const PAGES: usize = 2;
+
+    fn with_slabs<F: FnOnce(&mut Slabs, Page)>(f: F) {
+        let layout = Layout::from_size_align(PAGES * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let memory = unsafe { alloc(layout.clone()) };
+        assert!(!memory.is_null());
+        let mut pages = [UNUSED_SLAB_PAGE; PAGES];
+        {
+            // built from the page number, see the tests of `area_allocator`
+            let start = Page::from_number(memory as usize / PAGE_SIZE);
+            let mut slabs = Slabs::new(start, &mut pages);
+            f(&mut slabs, start);
+        }
+        unsafe { dealloc(memory, layout) };
+    }
+
+    #[test]
+    fn page_is_returned_when_empty() {
+        with_slabs(|slabs, start| {
+            // 2 KiB objects, two per page
+            assert_eq!(slabs.allocate(5), None);
+            unsafe { slabs.add_page(start, 5); }
+            let first = slabs.allocate(5).unwrap();
+            let second = slabs.allocate(5).unwrap();
+            assert_eq!(first.as_ptr() as usize, start.start_address());
+            assert_eq!(second.as_ptr() as usize, start.start_address() + 2048);
+            assert_eq!(slabs.allocate(5), None);
+            assert!(!slabs.has_empty_pages(0));
+
+            unsafe { slabs.deallocate(second, 5); }
+            assert!(!slabs.has_empty_pages(0));
+            // the freed object is handed out again
+            assert_eq!(slabs.allocate(5), Some(second));
+
+            unsafe {
+                slabs.deallocate(first, 5);
+                slabs.deallocate(second, 5);
+            }
+            assert!(slabs.has_empty_pages(0));
+            assert!(!slabs.has_empty_pages(1));
+            assert_eq!(slabs.take_empty_page(1), None);
+            assert_eq!(slabs.take_empty_page(0), Some(start));
+            assert_eq!(slabs.allocate(5), None);
+        });
+    }
+
+    #[test]
+    fn each_empty_page_is_taken_back() {
+        with_slabs(|slabs, start| {
+            unsafe {
+                slabs.add_page(start, 6);
+                slabs.add_page(start + 1, 6);
+            }
+            // 4 KiB objects, the most recently added page comes first
+            let first = slabs.allocate(6).unwrap();
+            let second = slabs.allocate(6).unwrap();
+            assert_eq!(slabs.allocate(6), None);
+
+            assert_eq!(first.as_ptr() as usize, (start + 1).start_address());
+            assert_eq!(second.as_ptr() as usize, start.start_address());
+
+            unsafe { slabs.deallocate(first, 6); }
+            assert_eq!(slabs.take_empty_page(0), Some(start + 1));
+            unsafe { slabs.deallocate(second, 6); }
+            assert_eq!(slabs.take_empty_page(0), Some(start));
+            assert_eq!(slabs.take_empty_page(0), None);
+        });
+    }
+}
@@ -1,19 +1,206 @@
-use memory::paging::{Page, ActivePageTable, PageIter, PAGE_SIZE, EntryFlags};
+use core::slice;
+
+use alloc::vec::Vec;
+
+use sync::IrqMutex;
+
+use memory::paging::{self, Page, PageIter, ActivePageTable, PAGE_SIZE, EntryFlags};
+
+/// Newly allocated stacks are filled with this, `boot.asm` paints the boot stack with it too
+const STACK_PAINT: u32 = 0x57ac57ac;
+
+/// Taken by the page fault handler to look up guard pages, so it must not be
+/// interrupted while held
+static STACK_ALLOCATOR: IrqMutex<Option<StackAllocator>> = IrqMutex::new(None);
+
+extern {
+    static stack_bottom: u8;
+    static stack_top: u8;
+}
+
+/// Set up the stack allocator for the pages in `page_range`.
+/// Must be called once, and only once, after the heap is initialized.
+pub fn init(page_range: PageIter) {
+    *STACK_ALLOCATOR.lock() = Some(StackAllocator::new(page_range));
+}
+
+pub fn alloc_stack(size_in_pages: usize, owner: &'static str) -> Option<Stack> {
+    if let Some(ref mut allocator) = *STACK_ALLOCATOR.lock() {
+        allocator.alloc_stack(size_in_pages, owner)
+    } else {
+        panic!("stack allocator not initialized");
+    }
+}
+
+/// Unmap the page below the boot stack, so an overflow of the boot stack
+/// faults instead of running into the memory below. It held the boot P2
+/// table, which is not used any more once the kernel is remapped. The frame
+/// is part of the kernel image, so it is not given to the frame allocator.
+pub fn unmap_boot_stack_guard_page(active_table: &mut ActivePageTable) {
+    let guard_page = Page::containing_address(boot_stack().bottom - PAGE_SIZE);
+    let (result, _frame) = active_table.unmap_return(guard_page, false);
+    result.flush(active_table);
+}
+
+/// The boot stack, which the kernel main loop runs on
+fn boot_stack() -> StackInfo {
+    unsafe {
+        StackInfo {
+            owner: "boot",
+            top: &stack_top as *const u8 as usize,
+            bottom: &stack_bottom as *const u8 as usize,
+        }
+    }
+}
+
+/// Find the stack whose guard page contains `address`.
+/// Called from the page fault handler, so it gives up instead of
+/// spinning if the fault interrupted the stack allocator.
+pub fn stack_for_guard_page(address: usize) -> Option<StackInfo> {
+    let boot_stack = boot_stack();
+    if address >= boot_stack.bottom - PAGE_SIZE && address < boot_stack.bottom {
+        return Some(boot_stack);
+    }
+
+    let allocator = match STACK_ALLOCATOR.try_lock() {
+        Some(allocator) => allocator,
+        None => return None,
+    };
+    if let Some(ref allocator) = *allocator {
+        allocator.stacks.iter()
+                        .find(|stack| address >= stack.bottom - PAGE_SIZE && address < stack.bottom)
+                        .map(|stack| *stack)
+    } else {
+        None
+    }
+}
+
+unsafe fn paint(bottom: usize, top: usize) {
+    let words = slice::from_raw_parts_mut(bottom as *mut u32, (top - bottom) / 4);
+    for word in words.iter_mut() {
+        *word = STACK_PAINT;
+    }
+}
+
+/// Deepest usage of a painted stack in bytes, the distance from the top
+/// to the lowest word that does not hold the paint any more
+unsafe fn max_usage(bottom: usize, top: usize) -> usize {
+    let words = slice::from_raw_parts(bottom as *const u32, (top - bottom) / 4);
+    let untouched = words.iter().take_while(|&&word| word == STACK_PAINT).count();
+    top - bottom - untouched * 4
+}
+
+/// Deepest usage and size of the boot stack
+fn boot_stack_max_usage() -> (usize, usize) {
+    let stack = boot_stack();
+    (unsafe { max_usage(stack.bottom, stack.top) }, stack.top - stack.bottom)
+}
+
+/// Print the deepest usage seen so far of the boot stack and all allocated stacks
+pub fn print_stack_usage() {
+    let (used, size) = boot_stack_max_usage();
+    println!("stack usage:\n  boot: {} of {} bytes", used, size);
+
+    if let Some(ref allocator) = *STACK_ALLOCATOR.lock() {
+        // stacks are unregistered with the lock held before they are unmapped
+        for stack in allocator.stacks.iter() {
+            println!("  {}: {} of {} bytes", stack.owner,
+                     unsafe { max_usage(stack.bottom, stack.top) }, stack.top - stack.bottom);
+        }
+    }
+}
+
+/// A stack handed out by the allocator
+#[derive(Debug, Clone, Copy)]
+pub struct StackInfo {
+    pub owner: &'static str,
+    pub top: usize,
+    pub bottom: usize,
+}
+
+/// Range of free pages that held a stack and its guard page
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    start: Page,
+    size_in_pages: usize,
+}
+
+impl Slot {
+    fn end(&self) -> Page {
+        self.start + self.size_in_pages
+    }
+}
 
 pub struct StackAllocator {
     range: PageIter,
+    /// Slots of freed stacks, sorted by address, adjacent slots are merged
+    free_slots: Vec<Slot>,
+    /// Stacks currently in use
+    stacks: Vec<StackInfo>,
 }
 
 impl StackAllocator {
     pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+        StackAllocator {
+            range: page_range,
+            free_slots: Vec::new(),
+            stacks: Vec::new(),
+        }
     }
 
-    pub fn alloc_stack(&mut self, active_table: &mut ActivePageTable, size_in_pages: usize) -> Option<Stack> {
+    pub fn alloc_stack(&mut self, size_in_pages: usize, owner: &'static str) -> Option<Stack> {
         if size_in_pages == 0 {
             return None; /* a zero sized stack makes no sense */
         }
 
+        let (start, end) = match self.reuse_slot(size_in_pages) {
+            Some(stack_pages) => stack_pages,
+            None => match self.take_from_range(size_in_pages) {
+                Some(stack_pages) => stack_pages,
+                None => return None, /* not enough pages */
+            },
+        };
+
+        // map stack pages to physical frames
+        paging::with_active_table(|active_table| {
+            for page in Page::range_inclusive(start, end) {
+                let result = active_table.map(page, EntryFlags::WRITABLE);
+                result.flush(active_table);
+            }
+        });
+
+        // create a new stack
+        let top_of_stack = end.start_address() + PAGE_SIZE;
+        unsafe { paint(start.start_address(), top_of_stack); }
+        let stack = Stack::new(top_of_stack, start.start_address(), owner);
+        self.stacks.push(stack.info());
+        Some(stack)
+    }
+
+    /// Take the stack pages out of the first freed slot that is big enough
+    /// for them and a guard page. Returns the first and last stack page.
+    fn reuse_slot(&mut self, size_in_pages: usize) -> Option<(Page, Page)> {
+        let needed = size_in_pages + 1;
+        let index = match self.free_slots.iter().position(|slot| slot.size_in_pages >= needed) {
+            Some(index) => index,
+            None => return None,
+        };
+
+        // use the top of the slot, the rest stays free and keeps its own
+        // guard page at its start, so the new guard page separates the two
+        let slot = self.free_slots[index];
+        if slot.size_in_pages == needed {
+            self.free_slots.remove(index);
+        } else {
+            self.free_slots[index].size_in_pages -= needed;
+        }
+
+        let guard_page = slot.start + (slot.size_in_pages - needed);
+        Some((guard_page + 1, guard_page + size_in_pages))
+    }
+
+    /// Take a guard page and the stack pages from the never used part of the range
+    fn take_from_range(&mut self, size_in_pages: usize) -> Option<(Page, Page)> {
         // clone the range, since we only want to change it on success
         let mut range = self.range.clone();
 
@@ -32,37 +219,82 @@ impl StackAllocator {
             (Some(_), Some(start), Some(end)) => {
                 // success! write back updated range
                 self.range = range;
+                Some((start, end))
+            }
+            _ => None,
+        }
+    }
 
-                // map stack pages to physical frames
-                for page in Page::range_inclusive(start, end) {
-                    let result = active_table.map(page, EntryFlags::WRITABLE);
-                    result.flush(active_table);
-                }
-
-                // create a new stack
-                let top_of_stack = end.start_address() + PAGE_SIZE;
-                Some(Stack::new(top_of_stack, start.start_address()))
+    /// Unmap the pages of a stack, free its frames and make its slot
+    /// (the stack together with the guard page below it) available again
+    fn free_stack(&mut self, stack: &Stack) {
+        let start = Page::containing_address(stack.bottom());
+        let size_in_pages = (stack.top() - stack.bottom()) / PAGE_SIZE;
+        self.stacks.retain(|info| info.bottom != stack.bottom());
+        paging::with_active_table(|active_table| {
+            for i in 0..size_in_pages {
+                let result = active_table.unmap(start + i);
+                result.flush(active_table);
             }
-            _ => None, /* not enough pages */
+        });
+
+        let slot = Slot {
+            start: Page::containing_address(stack.bottom() - PAGE_SIZE),
+            size_in_pages: size_in_pages + 1,
+        };
+        let index = self.free_slots.iter().position(|free| free.start > slot.start)
+                                   .unwrap_or(self.free_slots.len());
+        self.free_slots.insert(index, slot);
+
+        // merge with the following and the preceding slot
+        if index + 1 < self.free_slots.len() && self.free_slots[index].end() == self.free_slots[index + 1].start {
+            let next = self.free_slots.remove(index + 1);
+            self.free_slots[index].size_in_pages += next.size_in_pages;
+        }
+        if index > 0 && self.free_slots[index - 1].end() == self.free_slots[index].start {
+            let current = self.free_slots.remove(index);
+            self.free_slots[index - 1].size_in_pages += current.size_in_pages;
         }
     }
 }
 
+/// A kernel stack. Its pages are unmapped and its frames freed on drop,
+/// so a stack must outlive everything running on it.
 #[derive(Debug)]
 pub struct Stack {
     top: usize,
     bottom: usize,
+    owner: &'static str,
 }
 
 impl Stack {
-    fn new(top: usize, bottom: usize) -> Stack {
+    fn new(top: usize, bottom: usize, owner: &'static str) -> Stack {
         assert!(top > bottom);
         Stack {
             top: top,
             bottom: bottom,
+            owner: owner,
+        }
+    }
+
+    fn info(&self) -> StackInfo {
+        StackInfo {
+            owner: self.owner,
+            top: self.top,
+            bottom: self.bottom,
         }
     }
 
+    /// Deepest usage of the stack seen so far in bytes
+    pub fn max_usage(&self) -> usize {
+        unsafe { max_usage(self.bottom, self.top) }
+    }
+
+    /// Name given when the stack was allocated
+    pub fn owner(&self) -> &'static str {
+        self.owner
+    }
+
     pub fn top(&self) -> usize {
         self.top
     }
@@ -70,4 +302,12 @@ impl Stack {
     pub fn bottom(&self) -> usize {
         self.bottom
     }
-}
\ No newline at end of file
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        if let Some(ref mut allocator) = *STACK_ALLOCATOR.lock() {
+            allocator.free_stack(self);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Virtually contiguous allocations backed by frames that do not have to be
+//! physically contiguous. Every allocation is surrounded by unmapped guard
+//! pages, so running off either end of a buffer faults instead of silently
+//! corrupting the neighbouring allocation.
+
+use core::mem;
+use core::ptr::NonNull;
+
+use sync::IrqMutex;
+
+use memory::allocate_frame;
+use memory::area_allocator::AreaAllocator;
+use memory::paging::{self, Page, ActivePageTable, EntryFlags, PAGE_SIZE};
+
+pub const VMALLOC_START: usize = 0o_000_002_000_000_0000;
+/// Virtual memory reserved for vmalloc allocations and their guard pages
+pub const VMALLOC_SIZE: usize = 256 * 1024 * 1024; // 256 MiB
+
+const VMALLOC_PAGES: usize = VMALLOC_SIZE / PAGE_SIZE;
+const VMALLOC_BITMAP_SIZE: usize = VMALLOC_PAGES / (mem::size_of::<usize>() * 8);
+
+/// Unmapped pages placed before and after every allocation
+const GUARD_PAGES: usize = 1;
+
+static mut VMALLOC_BITMAP: [usize; VMALLOC_BITMAP_SIZE] = [0; VMALLOC_BITMAP_SIZE];
+
+static VMALLOC_AREA: IrqMutex<Option<AreaAllocator<'static>>> = IrqMutex::new(None);
+
+/// Must be called once, and only once.
+pub unsafe fn init() {
+    *VMALLOC_AREA.lock() = Some(AreaAllocator::new(&mut VMALLOC_BITMAP,
+                                                   Page::containing_address(VMALLOC_START),
+                                                   VMALLOC_PAGES));
+}
+
+fn reserve(count: usize) -> Option<Page> {
+    if let Some(ref mut area) = *VMALLOC_AREA.lock() {
+        area.allocate(count)
+    } else {
+        panic!("vmalloc: not initialized");
+    }
+}
+
+fn release(start: Page, count: usize) {
+    if let Some(ref mut area) = *VMALLOC_AREA.lock() {
+        area.deallocate(start, count)
+    } else {
+        panic!("vmalloc: not initialized");
+    }
+}
+
+/// Allocate `size` bytes of virtually contiguous, page aligned memory.
+/// The memory is not zeroed. Returns None if there are not enough frames
+/// or no free virtual range is big enough.
+pub fn vmalloc(size: usize) -> Option<NonNull<u8>> {
+    if size == 0 {
+        return None;
+    }
+
+    let count = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let area_start = match reserve(count + 2 * GUARD_PAGES) {
+        Some(start) => start,
+        None => return None,
+    };
+    let start = area_start + GUARD_PAGES;
+
+    let mapped = paging::with_active_table(|active_table| {
+        for i in 0..count {
+            match allocate_frame() {
+                Some(frame) => {
+                    let result = active_table.map_to(start + i, frame, EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE);
+                    result.flush(active_table);
+                }
+                None => {
+                    unmap_pages(active_table, start, i);
+                    return false;
+                }
+            }
+        }
+        true
+    });
+
+    if !mapped {
+        release(area_start, count + 2 * GUARD_PAGES);
+        return None;
+    }
+    NonNull::new(start.start_address() as *mut u8)
+}
+
+/// Unmap and free memory returned by `vmalloc`.
+/// The size is found by walking the pages up to the trailing guard page.
+pub unsafe fn vfree(ptr: NonNull<u8>) {
+    let address = ptr.as_ptr() as usize;
+    assert!(address >= VMALLOC_START && address < VMALLOC_START + VMALLOC_SIZE,
+            "vfree: {:#x} was not allocated by vmalloc", address);
+    assert!(address % PAGE_SIZE == 0, "vfree: {:#x} is not the start of an allocation", address);
+
+    let start = Page::containing_address(address);
+    let area_start = Page::containing_address(address - GUARD_PAGES * PAGE_SIZE);
+    let count = paging::with_active_table(|active_table| {
+        assert!(active_table.translate_page(area_start).is_none(),
+                "vfree: {:#x} is not the start of an allocation", address);
+
+        let mut count = 0;
+        while active_table.translate_page(start + count).is_some() {
+            count += 1;
+        }
+        assert!(count > 0, "vfree: {:#x} is not allocated", address);
+
+        unmap_pages(active_table, start, count);
+        count
+    });
+    release(area_start, count + 2 * GUARD_PAGES);
+}
+
+fn unmap_pages(active_table: &mut ActivePageTable, start: Page, count: usize) {
+    for i in 0..count {
+        let result = active_table.unmap(start + i);
+        result.flush(active_table);
+    }
+}
@@ -0,0 +1,215 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use sync::IrqMutex;
+
+use memory::paging::{Page, ActivePageTable, EntryFlags, PAGE_SIZE};
+
+/// Called with the page that was taken away from a reclaimable region,
+/// after it has been unmapped.
+pub type ReclaimCallback = fn(page: Page);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageState {
+    Unmapped,
+    Active,
+    Inactive,
+}
+
+/// A range of virtual memory whose pages are tracked by the scanner
+struct Region {
+    name: &'static str,
+    start: Page,
+    states: Vec<PageState>,
+    /// Indices of pages referenced since the previous scans
+    active: VecDeque<usize>,
+    /// Indices of pages not referenced during the last scan, least recently used first
+    inactive: VecDeque<usize>,
+    on_reclaim: Option<ReclaimCallback>,
+}
+
+impl Region {
+    fn page(&self, index: usize) -> Page {
+        self.start + index
+    }
+
+    /// Sort the pages into the active and inactive lists by their accessed
+    /// bits. The lists are rotated in place, both were created with room for
+    /// every page of the region, so this never allocates.
+    fn scan(&mut self, active_table: &mut ActivePageTable) {
+        let inactive = self.inactive.len();
+        let active = self.active.len();
+
+        // inactive pages keep their order unless they were referenced again
+        for _ in 0..inactive {
+            let index = self.inactive.pop_front().unwrap();
+            self.requeue(active_table, index);
+        }
+
+        // active pages that were not referenced become the newest inactive pages,
+        // the pages moved over from the inactive list are behind them
+        for _ in 0..active {
+            let index = self.active.pop_front().unwrap();
+            self.requeue(active_table, index);
+        }
+
+        // pick up pages mapped since the last scan
+        for index in 0..self.states.len() {
+            if self.states[index] == PageState::Unmapped {
+                let page = self.page(index);
+                if let Some(true) = test_and_clear_accessed(active_table, page) {
+                    self.states[index] = PageState::Active;
+                    self.active.push_back(index);
+                } else if active_table.flags(page).is_some() {
+                    self.states[index] = PageState::Inactive;
+                    self.inactive.push_back(index);
+                }
+            }
+        }
+    }
+
+    /// Append the page to the list matching its accessed bit, or forget it
+    /// if it was unmapped
+    fn requeue(&mut self, active_table: &mut ActivePageTable, index: usize) {
+        match test_and_clear_accessed(active_table, self.page(index)) {
+            Some(true) => {
+                self.states[index] = PageState::Active;
+                self.active.push_back(index);
+            }
+            Some(false) => {
+                self.states[index] = PageState::Inactive;
+                self.inactive.push_back(index);
+            }
+            None => self.states[index] = PageState::Unmapped,
+        }
+    }
+
+    /// Unmap up to `target` clean inactive pages, oldest first.
+    /// Returns the number of freed pages.
+    fn reclaim(&mut self, active_table: &mut ActivePageTable, target: usize) -> usize {
+        let on_reclaim = match self.on_reclaim {
+            Some(on_reclaim) => on_reclaim,
+            None => return 0,
+        };
+
+        let mut freed = 0;
+        let mut skipped = 0;
+        let mut remaining = self.inactive.len();
+        while freed < target && remaining > 0 {
+            let index = self.inactive.pop_front().unwrap();
+            remaining -= 1;
+            let page = self.page(index);
+            match active_table.flags(page) {
+                Some(flags) if flags.contains(EntryFlags::DIRTY) => {
+                    // we have no place to write the contents to
+                    self.inactive.push_back(index);
+                    skipped += 1;
+                }
+                Some(_) => {
+                    let result = active_table.unmap(page);
+                    result.flush(active_table);
+                    self.states[index] = PageState::Unmapped;
+                    on_reclaim(page);
+                    freed += 1;
+                }
+                None => self.states[index] = PageState::Unmapped,
+            }
+        }
+
+        // rotate the skipped pages from the back to the front again, so they
+        // keep their age
+        for _ in 0..self.inactive.len() - skipped {
+            let index = self.inactive.pop_front().unwrap();
+            self.inactive.push_back(index);
+        }
+        freed
+    }
+}
+
+lazy_static! {
+    /// Taken by the working set shrinker, which runs inside allocations
+    static ref REGIONS: IrqMutex<Vec<Region>> = IrqMutex::new(Vec::new());
+}
+
+/// Returns whether the page was accessed since the last call,
+/// or None if the page is not mapped.
+fn test_and_clear_accessed(active_table: &mut ActivePageTable, page: Page) -> Option<bool> {
+    let flags = match active_table.flags(page) {
+        Some(flags) => flags,
+        None => return None,
+    };
+    if flags.contains(EntryFlags::ACCESSED) {
+        let result = active_table.set_flags(page, flags - EntryFlags::ACCESSED);
+        result.flush(active_table);
+        Some(true)
+    } else {
+        Some(false)
+    }
+}
+
+/// Start tracking the working set of `size_in_pages` pages starting at `start`.
+/// Pages of regions with a reclaim callback may be unmapped and freed when
+/// they are clean and have not been used recently.
+pub fn register_region(name: &'static str, start: Page, size_in_pages: usize,
+                       on_reclaim: Option<ReclaimCallback>) {
+    // the lists get room for every page, so scans never allocate
+    let mut states = Vec::with_capacity(size_in_pages);
+    states.resize(size_in_pages, PageState::Unmapped);
+    let region = Region {
+        name: name,
+        start: start,
+        states: states,
+        active: VecDeque::with_capacity(size_in_pages),
+        inactive: VecDeque::with_capacity(size_in_pages),
+        on_reclaim: on_reclaim,
+    };
+    REGIONS.lock().push(region);
+}
+
+pub fn unregister_region(start: Page) {
+    REGIONS.lock().retain(|region| region.start != start);
+}
+
+/// Sample and clear the accessed bits of all tracked pages.
+/// Should be called periodically, the interval defines what "recently used" means.
+pub fn scan(active_table: &mut ActivePageTable) {
+    for region in REGIONS.lock().iter_mut() {
+        region.scan(active_table);
+    }
+}
+
+/// Free up to `target` clean, reclaimable pages that were not used recently.
+/// Returns the number of freed pages. It runs as a shrinker when an
+/// allocation fails, which may happen while the regions are locked, so it
+/// gives up instead of waiting for the lock.
+pub fn reclaim(active_table: &mut ActivePageTable, target: usize) -> usize {
+    let mut regions = match REGIONS.try_lock() {
+        Some(regions) => regions,
+        None => return 0,
+    };
+    let mut freed = 0;
+    for region in regions.iter_mut() {
+        if freed >= target {
+            break;
+        }
+        freed += region.reclaim(active_table, target - freed);
+    }
+    freed
+}
+
+/// Number of pages of the region starting at `start` used since the last scan
+pub fn working_set_size(start: Page) -> Option<usize> {
+    REGIONS.lock().iter()
+        .find(|region| region.start == start)
+        .map(|region| region.active.len())
+}
+
+pub fn print_working_sets() {
+    println!("working sets:");
+    for region in REGIONS.lock().iter() {
+        println!("  {}: start: {:#x}, active: {} KiB, inactive: {} KiB",
+                 region.name, region.start.start_address(),
+                 region.active.len() * PAGE_SIZE / 1024,
+                 region.inactive.len() * PAGE_SIZE / 1024);
+    }
+}
@@ -0,0 +1,71 @@
+//! Locks shared with interrupt handlers. An interrupt arriving while the
+//! interrupted code holds such a lock would spin on it forever, so
+//! interrupts are disabled for as long as it is held.
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use spin::{Mutex, MutexGuard};
+
+use cpu;
+
+/// Spinlock that disables interrupts while it is held, and restores the
+/// previous interrupt state when it is released
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T: 'a> {
+    /// Released before interrupts are restored
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> IrqMutex<T> {
+        IrqMutex { inner: Mutex::new(value) }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let interrupts_enabled = cpu::disable_interrupts();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_enabled: interrupts_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let interrupts_enabled = cpu::disable_interrupts();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_enabled: interrupts_enabled,
+            }),
+            None => {
+                cpu::restore_interrupts(interrupts_enabled);
+                None
+            }
+        }
+    }
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
+
+impl<'a, T> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard); }
+        cpu::restore_interrupts(self.interrupts_enabled);
+    }
+}